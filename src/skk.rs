@@ -13,7 +13,7 @@ mod test_unix;
 use daemonize::Daemonize;
 use rustc_hash::FxHashMap;
 
-use crate::skk::yaskkserv2::Yaskkserv2;
+use crate::skk::yaskkserv2::{Cidr, Yaskkserv2};
 use crate::skk::yaskkserv2_make_dictionary::Yaskkserv2MakeDictionary;
 
 pub(in crate::skk) use crate::skk::error::SkkError;
@@ -35,15 +35,25 @@ const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 const DICTIONARY_VERSION: u32 = 1;
 const DEFAULT_PORT: i32 = 1178;
 const DEFAULT_MAX_CONNECTIONS: i32 = 16;
+const DEFAULT_WORKER_THREADS: i32 = 1;
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 0;
 const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_LISTEN_UNIX_SOCKET_MODE: u32 = 0o600;
 const DEFAULT_CONFIG_FULL_PATH: &str = "/etc/yaskkserv2.conf";
-const DEFAULT_HOSTNAME_AND_IP_ADDRESS_FOR_PROTOCOL_3: &str = "localhost:127.0.0.1";
 const DEFAULT_GOOGLE_TIMEOUT_MILLISECONDS: u64 = 1000;
 const DEFAULT_GOOGLE_CACHE_FULL_PATH: &str = "/tmp/yaskkserv2.google_cache";
 const DEFAULT_GOOGLE_CACHE_ENTRIES: usize = 1024;
 const DEFAULT_GOOGLE_CACHE_EXPIRE_SECONDS: u64 = 30 * 24 * 60 * 60;
 const DEFAULT_GOOGLE_MAX_CANDIDATES_LENGTH: usize = 5 * 5;
+const DEFAULT_GOOGLE_CIRCUIT_BREAKER_FAILURES: u32 = 3;
+const DEFAULT_GOOGLE_CIRCUIT_BREAKER_COOLDOWN_SECONDS: u64 = 60;
+const DEFAULT_GOOGLE_REQUESTS_PER_MINUTE: u32 = 0;
+const DEFAULT_GOOGLE_MINIMUM_MIDASHI_LENGTH: usize = 0;
+const DEFAULT_GOOGLE_MAXIMUM_MIDASHI_LENGTH: usize = 0;
 const DEFAULT_MAX_SERVER_COMPLETIONS: u32 = 64;
+const DEFAULT_ACCESS_LOG_MAX_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_ACCESS_LOG_ROTATE_FILES: usize = 4;
+const DEFAULT_WORKING_DIRECTORY: &str = "/";
 const GOOGLE_JAPANESE_INPUT_URL: &str = "://www.google.com/transliterate?langpair=ja-Hira|ja&text=";
 const GOOGLE_SUGGEST_URL: &str = "://www.google.com/complete/search?hl=ja&output=toolbar&q=";
 const JISYO_MAXIMUM_LINE_LENGTH: usize = 128 * 1024;
@@ -71,7 +81,31 @@ struct Candidates;
 pub(in crate::skk) struct Config {
     port: String,
     max_connections: i32,
-    listen_address: String,
+    worker_threads: i32,
+    idle_timeout_seconds: u64,
+    listen_addresses: Vec<String>,
+    listen_unix_socket: String,
+    listen_unix_socket_mode: u32,
+    control_socket: String,
+    control_command: String,
+    metrics_address: String,
+    access_log_full_path: String,
+    access_log_max_size: u64,
+    access_log_rotate_files: usize,
+    log_target: LogTarget,
+    log_level: LogLevel,
+    pid_file_full_path: String,
+    user: String,
+    group: String,
+    daemon_log_full_path: String,
+    working_directory: String,
+    is_sandbox_enabled: bool,
+    tls_port: String,
+    tls_certificate_full_path: String,
+    tls_private_key_full_path: String,
+    tls_client_ca_certificate_full_path: String,
+    allow_from: Vec<Cidr>,
+    deny_from: Vec<Cidr>,
     hostname_and_ip_address_for_protocol_3: String,
     dictionary_full_path: String,
     full_path: String,
@@ -81,19 +115,36 @@ pub(in crate::skk) struct Config {
     google_cache_entries: usize,
     google_cache_expire_seconds: u64,
     google_max_candidates_length: usize,
+    google_circuit_breaker_failures: u32,
+    google_circuit_breaker_cooldown_seconds: u64,
+    google_requests_per_minute: u32,
+    google_allow_regex: String,
+    google_deny_regex: String,
+    google_minimum_midashi_length: usize,
+    google_maximum_midashi_length: usize,
+    google_deny_okuri_ari: bool,
+    google_deny_digits: bool,
     max_server_completions: u32,
     google_insert_hiragana_only_candidate: bool,
     google_insert_katakana_only_candidate: bool,
     google_insert_hankaku_katakana_only_candidate: bool,
+    google_japanese_input_url: String,
+    google_suggest_url: String,
+    google_proxy: String,
+    google_ca_certificate_full_path: String,
     is_http_enabled: bool,
     is_google_cache_enabled: bool,
     is_google_suggest_enabled: bool,
     is_midashi_utf8: bool,
     encoding: Encoding,
     is_no_daemonize: bool,
+    is_stdio: bool,
     is_verbose: bool,
     #[cfg(test)]
     is_debug_send: bool,
+    /// TCP の接続元を取得できなかったものとして扱う
+    #[cfg(test)]
+    is_debug_peer_address_error: bool,
 }
 
 impl Config {
@@ -101,10 +152,10 @@ impl Config {
         Self {
             port: DEFAULT_PORT.to_string(),
             max_connections: DEFAULT_MAX_CONNECTIONS,
-            listen_address: String::from(DEFAULT_LISTEN_ADDRESS),
-            hostname_and_ip_address_for_protocol_3: String::from(
-                DEFAULT_HOSTNAME_AND_IP_ADDRESS_FOR_PROTOCOL_3,
-            ),
+            worker_threads: DEFAULT_WORKER_THREADS,
+            idle_timeout_seconds: DEFAULT_IDLE_TIMEOUT_SECONDS,
+            listen_addresses: vec![String::from(DEFAULT_LISTEN_ADDRESS)],
+            listen_unix_socket_mode: DEFAULT_LISTEN_UNIX_SOCKET_MODE,
             dictionary_full_path: String::new(),
             full_path: String::from(DEFAULT_CONFIG_FULL_PATH),
             google_timeout_milliseconds: DEFAULT_GOOGLE_TIMEOUT_MILLISECONDS,
@@ -113,14 +164,46 @@ impl Config {
             google_cache_entries: DEFAULT_GOOGLE_CACHE_ENTRIES,
             google_cache_expire_seconds: DEFAULT_GOOGLE_CACHE_EXPIRE_SECONDS,
             google_max_candidates_length: DEFAULT_GOOGLE_MAX_CANDIDATES_LENGTH,
+            google_circuit_breaker_failures: DEFAULT_GOOGLE_CIRCUIT_BREAKER_FAILURES,
+            google_circuit_breaker_cooldown_seconds:
+                DEFAULT_GOOGLE_CIRCUIT_BREAKER_COOLDOWN_SECONDS,
+            google_requests_per_minute: DEFAULT_GOOGLE_REQUESTS_PER_MINUTE,
+            google_minimum_midashi_length: DEFAULT_GOOGLE_MINIMUM_MIDASHI_LENGTH,
+            google_maximum_midashi_length: DEFAULT_GOOGLE_MAXIMUM_MIDASHI_LENGTH,
             max_server_completions: DEFAULT_MAX_SERVER_COMPLETIONS,
+            access_log_max_size: DEFAULT_ACCESS_LOG_MAX_SIZE,
+            access_log_rotate_files: DEFAULT_ACCESS_LOG_ROTATE_FILES,
+            working_directory: String::from(DEFAULT_WORKING_DIRECTORY),
             ..Self::default()
         }
     }
 
     define_builder!(port, String);
     define_builder!(max_connections, i32);
-    define_builder!(listen_address, String);
+    define_builder!(worker_threads, i32);
+    define_builder!(idle_timeout_seconds, u64);
+    define_builder!(listen_addresses, Vec<String>);
+    define_builder!(listen_unix_socket, String);
+    define_builder!(listen_unix_socket_mode, u32);
+    define_builder!(control_socket, String);
+    define_builder!(metrics_address, String);
+    define_builder!(access_log_full_path, String);
+    define_builder!(access_log_max_size, u64);
+    define_builder!(access_log_rotate_files, usize);
+    define_builder!(log_target, LogTarget);
+    define_builder!(log_level, LogLevel);
+    define_builder!(pid_file_full_path, String);
+    define_builder!(user, String);
+    define_builder!(group, String);
+    define_builder!(daemon_log_full_path, String);
+    define_builder!(working_directory, String);
+    define_builder!(is_sandbox_enabled, bool);
+    define_builder!(tls_port, String);
+    define_builder!(tls_certificate_full_path, String);
+    define_builder!(tls_private_key_full_path, String);
+    define_builder!(tls_client_ca_certificate_full_path, String);
+    define_builder!(allow_from, Vec<Cidr>);
+    define_builder!(deny_from, Vec<Cidr>);
     define_builder!(hostname_and_ip_address_for_protocol_3, String);
     define_builder!(dictionary_full_path, String);
     define_builder!(google_timeout_milliseconds, u64);
@@ -129,49 +212,55 @@ impl Config {
     define_builder!(google_cache_entries, usize);
     define_builder!(google_cache_expire_seconds, u64);
     define_builder!(google_max_candidates_length, usize);
+    define_builder!(google_circuit_breaker_failures, u32);
+    define_builder!(google_circuit_breaker_cooldown_seconds, u64);
+    define_builder!(google_requests_per_minute, u32);
+    define_builder!(google_allow_regex, String);
+    define_builder!(google_deny_regex, String);
+    define_builder!(google_minimum_midashi_length, usize);
+    define_builder!(google_maximum_midashi_length, usize);
+    define_builder!(google_deny_okuri_ari, bool);
+    define_builder!(google_deny_digits, bool);
     define_builder!(max_server_completions, u32);
+    define_builder!(google_japanese_input_url, String);
+    define_builder!(google_suggest_url, String);
+    define_builder!(google_proxy, String);
+    define_builder!(google_ca_certificate_full_path, String);
     define_builder!(is_http_enabled, bool);
     define_builder!(is_google_cache_enabled, bool);
     define_builder!(is_google_suggest_enabled, bool);
     define_builder!(is_midashi_utf8, bool);
     define_builder!(encoding, Encoding);
     define_builder!(is_no_daemonize, bool);
+    define_builder!(is_stdio, bool);
     define_builder!(is_verbose, bool);
 
     #[cfg(test)]
     define_builder!(is_debug_send, bool);
+    #[cfg(test)]
+    define_builder!(is_debug_peer_address_error, bool);
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub(crate) enum Encoding {
+    #[default]
     Euc,
     Utf8,
 }
 
-impl Default for Encoding {
-    fn default() -> Self {
-        Self::Euc
-    }
-}
-
 impl std::fmt::Display for Encoding {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{self:?}")
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub(crate) enum EncodingOptions {
+    #[default]
     None,
     Bom,
 }
 
-impl Default for EncodingOptions {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 impl Encoding {
     fn from_u32(value: u32) -> Self {
         match value {
@@ -182,18 +271,34 @@ impl Encoding {
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Default, PartialEq)]
 enum GoogleTiming {
+    #[default]
     NotFound,
     Disable,
     Last,
     First,
 }
 
-impl Default for GoogleTiming {
-    fn default() -> Self {
-        Self::NotFound
-    }
+/// log の出力先
+#[derive(Clone, Debug, Default, PartialEq)]
+enum LogTarget {
+    #[default]
+    Syslog,
+    Stderr,
+    File(String),
+}
+
+/// 出力する log の level
+///
+/// 指定した level 以下 (`Error` 側) の log を出力する。
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
 }
 
 type DictionaryMidashiKey = [u8; 4];
@@ -499,26 +604,90 @@ pub fn run_yaskkserv2() -> Result<(), SkkError> {
     let mut config_file = yaskkserv2::config_file::Yaskkserv2ConfigFile::new(&command_line_config);
     config_file.read()?;
     let config = config_file.get_config();
+    #[cfg(unix)]
+    if !config.control_command.is_empty() {
+        return Yaskkserv2::run_control_client(&config, &config.control_command);
+    }
     core.setup(&config)?;
-    run_yaskkserv2_impl(&core, config.is_no_daemonize);
+    core.set_command_line_config(command_line_config);
+    if config.is_stdio {
+        core.run_stdio();
+        return Ok(());
+    }
+    #[cfg(unix)]
+    if Yaskkserv2::is_upgrade() {
+        run_yaskkserv2_upgrade(&mut core, &config);
+        return Ok(());
+    }
+    #[cfg(unix)]
+    if Yaskkserv2::is_socket_activation() {
+        run_yaskkserv2_socket_activation(&mut core);
+        return Ok(());
+    }
+    run_yaskkserv2_impl(&mut core, &config);
     Ok(())
 }
 
+/// systemd の socket activation で起動された場合は、 systemd が process を管理するので
+/// daemonize しない
 #[cfg(unix)]
-fn run_yaskkserv2_impl(core: &Yaskkserv2, is_no_daemonize: bool) {
-    if is_no_daemonize {
+fn run_yaskkserv2_socket_activation(core: &mut Yaskkserv2) {
+    core.run_socket_activation();
+}
+
+/// upgrade 元の process が daemonize 済みなので daemonize しない
+///
+/// pid file は upgrade 元の process が書き換える。
+#[cfg(unix)]
+fn run_yaskkserv2_upgrade(core: &mut Yaskkserv2, config: &Config) {
+    core.run_upgrade();
+    if !core.is_upgraded() {
+        Yaskkserv2::remove_pid_file(config);
+    }
+}
+
+/// daemonize する場合、 `pid_file_full_path` に pid を書き込み、 stdout と stderr を
+/// `daemon_log_full_path` へ redirect する
+///
+/// user と group の切り替えは bind し終えてから `Yaskkserv2::run()` で行う。
+#[cfg(unix)]
+fn run_yaskkserv2_impl(core: &mut Yaskkserv2, config: &Config) {
+    if config.is_no_daemonize {
         core.run();
-    } else {
-        let daemonize = Daemonize::new();
-        match daemonize.start() {
-            Ok(()) => core.run(),
-            Err(e) => println!("Error: {e}"),
+        return;
+    }
+    let mut daemonize = Daemonize::new().working_directory(&config.working_directory);
+    if !config.pid_file_full_path.is_empty() {
+        daemonize = daemonize.pid_file(&config.pid_file_full_path);
+    }
+    if !config.daemon_log_full_path.is_empty() {
+        let open_result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.daemon_log_full_path)
+            .and_then(|file| Ok((file.try_clone()?, file)));
+        match open_result {
+            Ok((stdout, stderr)) => daemonize = daemonize.stdout(stdout).stderr(stderr),
+            Err(e) => {
+                println!("Error: {e}");
+                return;
+            }
+        }
+    }
+    match daemonize.start() {
+        Ok(()) => {
+            core.run();
+            // upgrade した場合は新しい process の pid が書き込まれている
+            if !core.is_upgraded() {
+                Yaskkserv2::remove_pid_file(config);
+            }
         }
+        Err(e) => println!("Error: {e}"),
     }
 }
 
 #[cfg(not(unix))]
-fn run_yaskkserv2_impl(core: &Yaskkserv2, _is_no_daemonize: bool) {
+fn run_yaskkserv2_impl(core: &mut Yaskkserv2, _config: &Config) {
     core.run();
 }
 
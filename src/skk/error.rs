@@ -15,6 +15,8 @@ pub enum SkkError {
     Json(#[from] json::Error),
     #[error("{}", .0)]
     Bincode(#[from] bincode::Error),
+    #[error("{}", .0)]
+    Rustls(#[from] rustls::Error),
     #[error("JisyoRead error")]
     JisyoRead,
     #[error("BrokenCache error")]
@@ -29,4 +31,8 @@ pub enum SkkError {
     Encoding,
     #[error("Request error")]
     Request,
+    #[error("RequestSkipped error")]
+    RequestSkipped,
+    #[error("{}", .0)]
+    Control(String),
 }
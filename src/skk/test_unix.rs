@@ -97,6 +97,11 @@ impl Path {
         )
     }
 
+    /// setup で `cargo build --release` した yaskkserv2 の full path を返す
+    pub(in crate::skk) fn get_full_path_yaskkserv2_binary() -> String {
+        setup::get_yaskkserv2_binary_full_path()
+    }
+
     fn get_full_path_test_base() -> String {
         Self::get_full_path("")
     }
@@ -122,19 +127,14 @@ impl Path {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 enum Protocol {
+    #[default]
     Protocol1,
     Protocol4,
     Echo,
 }
 
-impl Default for Protocol {
-    fn default() -> Self {
-        Self::Protocol1
-    }
-}
-
 #[allow(clippy::struct_excessive_bools)]
 #[derive(Clone, Debug, Default)]
 struct ConnectSendCompareRunParameter {
@@ -169,7 +169,7 @@ fn echo_server_mio_raw_server(
                         token => {
                             let socket = sockets[usize::from(token)]
                                 .as_mut()
-                                .map_or_else(|| panic!("sockets get failed"), |socket| socket);
+                                .unwrap_or_else(|| panic!("sockets get failed"));
                             let mut is_exit = false;
                             while match socket.buffer_stream.read(&mut buffer) {
                                 Ok(0) => {
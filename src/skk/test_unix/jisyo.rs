@@ -156,7 +156,7 @@ impl Jisyo {
         Yaskkserv2MakeDictionary::run_create_dictionary(
             &config,
             &encoding_table,
-            &[self.test_jisyo_full_path.clone()],
+            std::slice::from_ref(&self.test_jisyo_full_path),
         )
         .unwrap();
         {
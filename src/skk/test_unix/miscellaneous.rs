@@ -3,6 +3,7 @@ use rustc_hash::FxHashSet;
 use std::fs::File;
 
 use crate::skk::test_unix::setup;
+use crate::skk::yaskkserv2::Cidr;
 use crate::skk::yaskkserv2_make_dictionary::JisyoReader;
 use crate::skk::{Candidates, Encoding};
 
@@ -150,3 +151,39 @@ fn remove_duplicates_test() {
         );
     }
 }
+
+#[test]
+fn cidr_test() {
+    let contains_table = [
+        ("192.168.0.0/16", "192.168.255.1", true),
+        ("192.168.0.0/16", "192.169.0.1", false),
+        ("192.168.0.0/16", "::ffff:192.168.0.1", true),
+        ("10.0.0.1", "10.0.0.1", true),
+        ("10.0.0.1", "10.0.0.2", false),
+        ("0.0.0.0/0", "203.0.113.1", true),
+        ("0.0.0.0/0", "::1", false),
+        ("fd00::/8", "fd12:3456::1", true),
+        ("fd00::/8", "fe80::1", false),
+        ("::1", "::1", true),
+        ("::/0", "2001:db8::1", true),
+    ];
+    for (cidr, ip_address, expected) in contains_table {
+        assert_eq!(
+            Cidr::parse(cidr)
+                .unwrap()
+                .contains(ip_address.parse().unwrap()),
+            expected,
+            "cidr={cidr} ip_address={ip_address}"
+        );
+    }
+    for illegal in [
+        "",
+        "192.168.0.0/33",
+        "::/129",
+        "192.168.0.0/",
+        "localhost",
+        "10.0.0.0/8/8",
+    ] {
+        assert!(Cidr::parse(illegal).is_none(), "illegal={illegal}");
+    }
+}
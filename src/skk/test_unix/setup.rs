@@ -16,6 +16,10 @@ static RE_DOT_MD5_FILE: std::sync::LazyLock<Regex> =
     std::sync::LazyLock::new(|| Regex::new(r"^([0-9a-f]{32})").unwrap());
 static RE_URL_FILENAME: std::sync::LazyLock<Regex> =
     std::sync::LazyLock::new(|| Regex::new(r"/([^/]+)$").unwrap());
+static RE_CARGO_EXECUTABLE: std::sync::LazyLock<Regex> =
+    std::sync::LazyLock::new(|| Regex::new(r#""executable":"([^"]+)""#).unwrap());
+static YASKKSERV2_BINARY_FULL_PATH: std::sync::LazyLock<RwLock<String>> =
+    std::sync::LazyLock::new(|| RwLock::new(String::new()));
 static EUC_JISYO_FULL_PATHS: std::sync::LazyLock<RwLock<Vec<String>>> =
     std::sync::LazyLock::new(|| RwLock::new(Vec::new()));
 static UTF8_JISYO_FULL_PATHS: std::sync::LazyLock<RwLock<Vec<String>>> =
@@ -397,14 +401,25 @@ impl OnceInit {
         }
     }
 
+    // current directory や `CARGO_TARGET_DIR` から binary の path を推測せず、 cargo が
+    // 報告する path を使う
     fn cargo_build() {
-        let mut child = std::process::Command::new("cargo")
+        let output = std::process::Command::new("cargo")
             .arg("build")
             .arg("--release")
             .arg("--bin=yaskkserv2")
-            .spawn()
+            .arg("--message-format=json-render-diagnostics")
+            .stderr(std::process::Stdio::inherit())
+            .output()
             .unwrap();
-        child.wait().unwrap();
+        assert!(output.status.success(), "cargo build yaskkserv2 failed");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let executable = stdout
+            .lines()
+            .find_map(|line| RE_CARGO_EXECUTABLE.captures(line))
+            .map(|captures| captures[1].to_string())
+            .expect("yaskkserv2 executable not found in cargo output");
+        *YASKKSERV2_BINARY_FULL_PATH.write().unwrap() = executable;
         let mut child = std::process::Command::new("cargo")
             .arg("build")
             .arg("--release")
@@ -544,6 +559,11 @@ int main(int argc, char *argv[]) {
     }
 }
 
+/// setup で build した yaskkserv2 の full path を返す
+pub(in crate::skk) fn get_yaskkserv2_binary_full_path() -> String {
+    YASKKSERV2_BINARY_FULL_PATH.read().unwrap().clone()
+}
+
 pub(in crate::skk) fn setup_and_wait(test_name: &str) {
     println!("wait setup {test_name}");
     let _init_lock = INIT_MUTEX_LOCK.lock().unwrap();
@@ -1,9 +1,10 @@
+use crate::skk::test_unix::yaskkserv2::{HttpStandIn, TlsCertificates};
 use crate::skk::test_unix::{
     encoding_simple, get_take_count, setup, wait_server, ConnectSendCompare,
     ConnectSendCompareRunParameter, Encoding, GoogleTiming, Path, Protocol,
     Yaskkserv2MakeDictionary, MANY_THREADS,
 };
-use crate::skk::yaskkserv2::Yaskkserv2;
+use crate::skk::yaskkserv2::{GoogleLimiter, Request, Yaskkserv2};
 use crate::skk::Config;
 
 #[cfg(test)]
@@ -96,7 +97,7 @@ impl Yaskkserv2MakeDictionaryTest {
             Yaskkserv2MakeDictionary::run_create_dictionary(
                 &config.clone(),
                 &encoding_table,
-                &[jisyo_full_path.clone()],
+                std::slice::from_ref(&jisyo_full_path),
             )
             .unwrap();
         }
@@ -394,7 +395,7 @@ fn yaskkserv2_benchmark_binary_normal_send_sequential_test() {
     let name = "yaskkserv2_benchmark_binary_normal_send_sequential";
     setup::setup_and_wait(name);
     let port = "12503";
-    let child = match std::process::Command::new("target/release/yaskkserv2")
+    let child = match std::process::Command::new(Path::get_full_path_yaskkserv2_binary())
         .arg("--no-daemonize")
         .arg(format!("--port={port}"))
         .arg(Path::get_full_path_yaskkserv2_dictionary(Encoding::Utf8))
@@ -440,6 +441,68 @@ fn yaskkserv2_benchmark_send_std_net_tcp_test() {
     setup::exit();
 }
 
+// Google Japanese Input の request ごとに client を作成した場合と、 client を使い回した場合の
+// latency を比較する benchmark
+//
+// network 環境に依存しないよう、 local の HTTPS server を Google Japanese Input の代わりに使う。
+// 使い回した場合は keep-alive により connection が 1 つになることも確認する。
+#[test]
+fn yaskkserv2_benchmark_google_client_test() {
+    const LOOP: u32 = 100;
+    let name = "yaskkserv2_benchmark_google_client";
+    setup::setup_and_wait(name);
+    let tls_certificates = TlsCertificates::new();
+    let ca_certificate_full_path =
+        Path::get_full_path("yaskkserv2_benchmark_google_client.ca_certificate.pem");
+    std::fs::write(&ca_certificate_full_path, &tls_certificates.ca_certificate).unwrap();
+    let config = Config::new().google_ca_certificate_full_path(ca_certificate_full_path);
+    let stand_in = HttpStandIn::new_tls("200 OK", r#"[["みだし",["見出し"]]]"#, &tls_certificates);
+    let url = stand_in.get_url();
+    let limiter = GoogleLimiter::new(&config);
+    let request = |client: &reqwest::blocking::Client| {
+        let candidates = Request::request_google_japanese_input(
+            client,
+            &limiter,
+            &url,
+            "みだし".as_bytes(),
+            config.google_max_candidates_length,
+            true,
+            true,
+            true,
+        )
+        .unwrap();
+        assert_eq!(candidates, vec!["見出し".as_bytes().to_vec()]);
+    };
+    let bench = std::time::Instant::now();
+    for _ in 0..LOOP {
+        request(&Request::create_client(&config).unwrap());
+    }
+    let new_client_elapsed = bench.elapsed();
+    let new_client_connections = stand_in.get_connections();
+    let client = Request::create_client(&config).unwrap();
+    let bench = std::time::Instant::now();
+    for _ in 0..LOOP {
+        request(&client);
+    }
+    let reused_client_elapsed = bench.elapsed();
+    let reused_client_connections = stand_in.get_connections() - new_client_connections;
+    println!(
+        "{name}  new client  total ms.={}  average us.={}  connections={new_client_connections}",
+        new_client_elapsed.as_millis(),
+        (new_client_elapsed / LOOP).as_micros()
+    );
+    println!(
+        "{name}  reused client  total ms.={}  average us.={}  connections={reused_client_connections}",
+        reused_client_elapsed.as_millis(),
+        (reused_client_elapsed / LOOP).as_micros()
+    );
+    assert_eq!(new_client_connections, LOOP as usize);
+    assert_eq!(reused_client_connections, 1);
+    drop(client);
+    stand_in.exit();
+    setup::exit();
+}
+
 #[test]
 fn yaskkserv2_make_dictionary_benchmark_create_dictionary_euc_euc_test() {
     let name = "yaskkserv2_make_dictionary_benchmark_create_dictionary_euc_euc";
@@ -9,10 +9,29 @@
 //! 使用も最低限に抑えてある (現代的な Rust が動作がするような環境に対して、いささか神経質に
 //! なり過ぎかもしれない)。
 
+mod access_log;
+mod cidr;
+#[cfg(unix)]
+mod control;
 mod dictionary_reader;
 mod google_cache;
+mod google_filter;
+mod google_limiter;
+mod google_worker;
+mod listener;
+mod logger;
+mod metrics;
+#[cfg(unix)]
+mod privilege;
 mod request;
+mod sandbox;
 mod server;
+#[cfg(unix)]
+mod signal;
+mod stdio;
+mod tls;
+#[cfg(unix)]
+mod upgrade;
 
 pub(in crate::skk) mod command_line;
 pub(in crate::skk) mod config_file;
@@ -23,12 +42,17 @@ pub(in crate::skk) mod test_unix;
 #[cfg(all(not(test), not(unix)))]
 use log::*;
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use regex::Regex;
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Seek, Write};
-use std::net::{Shutdown, SocketAddr};
-use std::sync::RwLock;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(all(not(test), unix))]
 use syslog::{Facility, Formatter3164};
 
@@ -36,8 +60,9 @@ use syslog::{Facility, Formatter3164};
 use crate::skk::PKG_NAME;
 use crate::skk::{
     encoding_simple, Candidates, Config, Dictionary, DictionaryBlockInformation,
-    DictionaryMidashiKey, Encoding, GoogleTiming, OnMemory, SkkError, GOOGLE_JAPANESE_INPUT_URL,
-    GOOGLE_SUGGEST_URL, PKG_VERSION, PROTOCOL_RESULT_ERROR, SHA1SUM_LENGTH,
+    DictionaryMidashiKey, Encoding, GoogleTiming, LogLevel, LogTarget, OnMemory, SkkError,
+    GOOGLE_JAPANESE_INPUT_URL, GOOGLE_SUGGEST_URL, PKG_VERSION, PROTOCOL_RESULT_ERROR,
+    SHA1SUM_LENGTH,
 };
 
 #[cfg(feature = "assert_paranoia")]
@@ -50,6 +75,47 @@ use crate::skk::test_unix::DEBUG_FORCE_EXIT_DIRECTORY;
 use crate::skk::yaskkserv2::server::test_unix::ServerDebug;
 
 const MAX_CONNECTION: usize = 1024;
+const MAX_WORKER_THREADS: usize = 64;
+const GOOGLE_WORKER_THREADS: usize = 4;
+
+const WAKER: Token = Token(MAX_CONNECTION);
+/// listener の token は `LISTENER_TOKEN_BASE + listeners の index` となる
+const LISTENER_TOKEN_BASE: usize = MAX_CONNECTION + 1;
+/// idle timeout が有効な場合に idle な connection を確認する間隔
+const IDLE_TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// SIGTERM などで終了する際に、処理中の response を送信し終えるまで待つ最大の時間
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// control socket は yaskkserv2 を操作できるので、所有者以外は使えないようにする
+#[cfg(unix)]
+const CONTROL_SOCKET_MODE: u32 = 0o600;
+/// control socket の client が response を受け取らないまま thread を止めないための timeout
+#[cfg(unix)]
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+/// control socket の client が 1 つの command を送り終えるまでの時間
+///
+/// control socket は 1 つの thread で client を順に処理するので、 command を送らない client
+/// が他の client を待たせないよう短くする。
+#[cfg(unix)]
+const CONTROL_COMMAND_DEADLINE: Duration = Duration::from_secs(1);
+
+/// upgrade で起動した新しい process が dictionary を読み込み ready を通知するまでの timeout
+#[cfg(unix)]
+const UPGRADE_READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// upgrade 後、古い process が既存の connection を処理し続ける時間
+#[cfg(unix)]
+// `Duration::from_mins()` は Rust 1.91 以降でしか使えないので使わない
+#[allow(clippy::duration_suboptimal_units)]
+const UPGRADE_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// upgrade で新しい process に渡す fd の環境変数
+#[cfg(unix)]
+const UPGRADE_LISTENER_FDS_ENV: &str = "YASKKSERV2_UPGRADE_LISTENER_FDS";
+#[cfg(unix)]
+const UPGRADE_CONTROL_FD_ENV: &str = "YASKKSERV2_UPGRADE_CONTROL_FD";
+#[cfg(unix)]
+const UPGRADE_METRICS_FD_ENV: &str = "YASKKSERV2_UPGRADE_METRICS_FD";
+#[cfg(unix)]
+const UPGRADE_READY_FD_ENV: &str = "YASKKSERV2_UPGRADE_READY_FD";
 
 const PROTOCOL_MAXIMUM_LENGTH: usize = {
     const SKKSERV_MAXIMUM_MIDASHI_LENGTH: usize = 510;
@@ -66,12 +132,33 @@ const MIDASHI_VEC_CAPACITY: usize = 1024;
 
 const INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH: usize = 8 * 1024;
 
+/// protocol `0` から `4` と、それ以外
+const STATISTICS_PROTOCOLS: usize = 6;
+
+/// `GoogleLimiter` の 1 request 分の token (1 分の milliseconds)
+const GOOGLE_LIMITER_TOKEN: u64 = 60 * 1000;
+
+/// Google Japanese Input と Google Suggest の latency の histogram の上限 (秒)
+const METRICS_LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// `/metrics` の client が 1 つの request を送り終えるまでの時間と、 response の write の timeout
+///
+/// `/metrics` は 1 つの thread で client を順に処理するので、 request を送らない client が
+/// 他の client を待たせないよう短くする。
+const METRICS_TIMEOUT: Duration = Duration::from_secs(2);
+/// `--stdio` の access log の peer
+const STDIO_PEER: &str = "stdio";
+
 static GOOGLE_CACHE_OBJECT: std::sync::LazyLock<RwLock<GoogleCacheObject>> =
     std::sync::LazyLock::new(|| RwLock::new(GoogleCacheObject::new()));
+/// Google cache の file への書き込みを 1 度に 1 つにするための lock
+static GOOGLE_CACHE_FILE_MUTEX_LOCK: Mutex<()> = Mutex::new(());
+static LOGGER: Mutex<Logger> = Mutex::new(Logger::new());
 
 pub(in crate::skk) enum HandleClientResult {
     Continue,
     Exit,
+    /// Google Japanese Input を呼ぶ必要がある protocol 1 の request (EUC)
+    Google(Vec<u8>),
 }
 
 enum RunLoopListenerResult {
@@ -84,11 +171,14 @@ enum RunLoopTokenResult {
     Return,
 }
 
+/// `generation` は open した時点の `LoadedDictionary` の `generation` で、 dictionary が reload
+/// された場合に開き直すためのもの。
 pub(in crate::skk) struct DictionaryFile {
     file: File,
     seek_position: u64,
     read_length: usize,
     buffer: Vec<u8>,
+    generation: u64,
 }
 
 impl DictionaryFile {
@@ -98,9 +188,19 @@ impl DictionaryFile {
             seek_position: 0,
             read_length: 0,
             buffer: vec![0; buffer_length],
+            generation: 0,
         }
     }
 
+    /// reload された dictionary を開き直す
+    fn reopen(&mut self, dictionary_full_path: &str, generation: u64) -> Result<(), SkkError> {
+        self.file = File::open(dictionary_full_path)?;
+        self.seek_position = 0;
+        self.read_length = 0;
+        self.generation = generation;
+        Ok(())
+    }
+
     fn read(&mut self, seek_position: u64, read_length: usize) -> Result<&[u8], SkkError> {
         if self.seek_position != seek_position || self.read_length != read_length {
             self.seek_position = seek_position;
@@ -115,16 +215,139 @@ impl DictionaryFile {
     }
 }
 
+/// `allow-from` と `deny-from` で指定する `ADDR/PREFIX-LENGTH` 形式の address 範囲
+///
+/// `/PREFIX-LENGTH` を省略した場合は `ADDR` のみとなる。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(in crate::skk) struct Cidr {
+    ip_address: IpAddr,
+    prefix_length: u8,
+}
+
+/// bind 済みの listener
+///
+/// poll loop の thread ごとに `try_clone_mio()` して登録する。
+/// `Unix` の `full_path` は終了時に削除する socket で、 systemd から継承した socket のように
+/// yaskkserv2 が作成したものでない場合は `None` となる。
+pub(in crate::skk) enum Listener {
+    Tcp(std::net::TcpListener),
+    Tls {
+        listener: std::net::TcpListener,
+        tls_config: Arc<rustls::ServerConfig>,
+    },
+    #[cfg(unix)]
+    Unix {
+        listener: std::os::unix::net::UnixListener,
+        full_path: Option<String>,
+    },
+}
+
+pub(in crate::skk) enum MioListener {
+    Tcp(TcpListener),
+    Tls {
+        listener: TcpListener,
+        tls_config: Arc<rustls::ServerConfig>,
+    },
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// client との接続
+///
+/// TCP と TLS と unix domain socket で `Server::handle_client()` などを共通化するためのもの。
+pub(in crate::skk) enum MioStream {
+    Tcp(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// `--stdio` で stdin と stdout を 1 つの client との接続として扱うためのもの
+struct StdioStream {
+    stdin: std::io::Stdin,
+    stdout: std::io::Stdout,
+}
+
+/// `serial` は token の再利用で別の connection に worker の結果を送信しないためのもの。
+/// `is_waiting_google` が true の間は worker の結果待ちで、次の request を読まない。
+/// `last_active` は idle timeout と、 max connections に達した場合に切断する connection の
+/// 選択に使う。
+/// `partial_request` は `WouldBlock` までに読めた途中までの request で、次に読んだ data の前に
+/// 連結する。
+/// response は `output_buffer` に書き込んでから送信する。 non-blocking な socket に一度で書き
+/// 込めなかった分は `output_buffer` に残し、 `Interest::WRITABLE` で書き込めるようになった時点
+/// で続きを送信する。 `is_writable_registered` は `Interest::WRITABLE` を登録中かを示す。
 struct MioSocket {
-    buffer_stream: BufReader<TcpStream>,
+    buffer_stream: BufReader<MioStream>,
+    serial: u64,
+    /// access log に書き込む client の address
+    peer: String,
+    is_waiting_google: bool,
+    last_active: Instant,
+    partial_request: Vec<u8>,
+    output_buffer: Vec<u8>,
+    is_writable_registered: bool,
 }
 
 impl MioSocket {
-    fn new(stream: TcpStream) -> Self {
+    fn new(stream: MioStream, serial: u64, peer: String) -> Self {
         Self {
             buffer_stream: BufReader::new(stream),
+            serial,
+            peer,
+            is_waiting_google: false,
+            last_active: Instant::now(),
+            partial_request: Vec::new(),
+            output_buffer: Vec::new(),
+            is_writable_registered: false,
         }
     }
+
+    /// `output_buffer` を socket へ書き込めるだけ書き込む
+    ///
+    /// `WouldBlock` の場合は残りを `output_buffer` に残して `Ok` を返す。
+    fn write_output_buffer(&mut self) -> Result<(), std::io::Error> {
+        let stream = self.buffer_stream.get_mut();
+        let mut written = 0;
+        let result = loop {
+            if written == self.output_buffer.len() {
+                break match stream.flush() {
+                    Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => Err(e),
+                    _ => Ok(()),
+                };
+            }
+            match stream.write(&self.output_buffer[written..]) {
+                Ok(0) => break Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(size) => written += size,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.output_buffer.drain(..written);
+        result
+    }
+
+    /// 送信待ちの response が残っているかを返す
+    fn is_write_pending(&self) -> bool {
+        !self.output_buffer.is_empty() || self.buffer_stream.get_ref().is_write_pending()
+    }
+
+    /// 送信待ちの有無に合わせて `Interest::WRITABLE` を登録または解除する
+    fn update_interest(&mut self, poll: &Poll, token: Token) -> Result<(), std::io::Error> {
+        let is_write_pending = self.is_write_pending();
+        if is_write_pending != self.is_writable_registered {
+            let interest = if is_write_pending {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            poll.registry()
+                .reregister(self.buffer_stream.get_mut(), token, interest)?;
+            self.is_writable_registered = is_write_pending;
+        }
+        Ok(())
+    }
 }
 
 pub(in crate::skk) trait TcpStreamSkk: Write {
@@ -160,28 +383,33 @@ pub(in crate::skk) trait TcpStreamSkk: Write {
     }
 }
 
-impl TcpStreamSkk for TcpStream {}
-impl TcpStreamSkk for &TcpStream {}
-impl TcpStreamSkk for std::net::TcpStream {}
-impl TcpStreamSkk for &std::net::TcpStream {}
+impl<W: Write> TcpStreamSkk for W {}
 
 trait BufReaderSkk {
     fn read_until_skk_server(&mut self, buffer: &mut Vec<u8>) -> Result<usize, std::io::Error>;
 }
 
-impl BufReaderSkk for BufReader<TcpStream> {
-    fn read_until_skk_server(&mut self, buffer: &mut Vec<u8>) -> Result<usize, std::io::Error> {
-        fn find_one_character_protocol(available: &[u8]) -> Option<usize> {
-            for (i, c) in available.iter().enumerate() {
-                if *c == b'0' || *c == b'2' || *c == b'3' {
-                    return Some(i);
-                }
-                if *c != b'\n' && *c != b'\r' {
-                    break;
-                }
+/// `received` に続く `available` の中で、 request の最後の index を返す
+///
+/// 1 文字の protocol ("0", "2", "3") は先頭の CR/LF を除いた最初の文字で判定する。
+/// `"2" "1midashi "` のように続けて送信された場合に、 space までを 1 つの request としない
+/// ことに注意。
+fn find_request_end(received: &[u8], available: &[u8]) -> Option<usize> {
+    if received.iter().all(|c| *c == b'\n' || *c == b'\r') {
+        for (i, c) in available.iter().enumerate() {
+            if *c == b'0' || *c == b'2' || *c == b'3' {
+                return Some(i);
+            }
+            if *c != b'\n' && *c != b'\r' {
+                break;
             }
-            None
         }
+    }
+    twoway::find_bytes(available, b" ")
+}
+
+impl<R: Read> BufReaderSkk for BufReader<R> {
+    fn read_until_skk_server(&mut self, buffer: &mut Vec<u8>) -> Result<usize, std::io::Error> {
         let mut read = 0;
         loop {
             let (done, used) = {
@@ -190,22 +418,13 @@ impl BufReaderSkk for BufReader<TcpStream> {
                     Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
                     Err(e) => return Err(e),
                 };
-                match twoway::find_bytes(available, b" ") {
-                    Some(i) => {
-                        buffer.extend_from_slice(&available[..=i]);
-                        (true, i + 1)
-                    }
-                    None =>
-                    {
-                        #[allow(clippy::option_if_let_else)]
-                        if let Some(i) = find_one_character_protocol(available) {
-                            buffer.extend_from_slice(&available[..=i]);
-                            (true, i + 1)
-                        } else {
-                            buffer.extend_from_slice(available);
-                            (false, available.len())
-                        }
-                    }
+                #[allow(clippy::option_if_let_else)]
+                if let Some(i) = find_request_end(buffer, available) {
+                    buffer.extend_from_slice(&available[..=i]);
+                    (true, i + 1)
+                } else {
+                    buffer.extend_from_slice(available);
+                    (false, available.len())
                 }
             };
             self.consume(used);
@@ -217,6 +436,70 @@ impl BufReaderSkk for BufReader<TcpStream> {
     }
 }
 
+/// `DeadlineStream` が read の前に残り時間を設定するためのもの
+trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error>;
+}
+
+impl ReadTimeout for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        Self::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        Self::set_read_timeout(self, timeout)
+    }
+}
+
+/// `restart_deadline()` から `timeout` を過ぎると read が `TimedOut` となる stream
+///
+/// read ごとの timeout だけでは、少しずつ送り続ける client が thread を止め続けられるので、
+/// read の前に deadline までの残り時間を timeout に設定する。
+struct DeadlineStream<S> {
+    stream: S,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl<S> DeadlineStream<S> {
+    fn new(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            timeout,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    #[cfg(unix)]
+    fn restart_deadline(&mut self) {
+        self.deadline = Instant::now() + self.timeout;
+    }
+}
+
+impl<S: ReadTimeout + Read> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.stream.flush()
+    }
+}
+
 // is_debug_force_exit_mode が true のとき、
 // "std::env::var("YASKKSERV2_TEST_DIRECTORY")/DEBUG_FORCE_EXIT_DIRECTORY" を server の強制
 // 終了 flag として使う。このディレクトリが存在した場合、ディレクトリを削除してから server loop
@@ -228,6 +511,17 @@ impl BufReaderSkk for BufReader<TcpStream> {
 //  このようなかたちとなっている)
 pub(in crate::skk) struct Yaskkserv2 {
     server: Server,
+    /// SIGHUP で config file を読み直す際に使う、 command line で指定された config
+    command_line_config: Option<Config>,
+    /// upgrade で起動する binary と引数
+    ///
+    /// binary が置き換えられた後では `current_exe()` で得られないため、起動時に保持する。
+    #[cfg(unix)]
+    upgrade_command: Option<(std::path::PathBuf, Vec<std::ffi::OsString>)>,
+    /// upgrade で新しい process に listening socket を渡したかどうか
+    ///
+    /// 渡した場合、 unix domain socket や pid file は新しい process が使うので削除しない。
+    is_upgraded: AtomicBool,
     #[cfg(test)]
     pub(in crate::skk) is_debug_force_exit_mode: bool,
 }
@@ -236,12 +530,19 @@ impl Yaskkserv2 {
     pub(in crate::skk) fn new() -> Self {
         Self {
             server: Server::new(),
+            command_line_config: None,
+            #[cfg(unix)]
+            upgrade_command: std::env::current_exe()
+                .ok()
+                .map(|full_path| (full_path, std::env::args_os().skip(1).collect())),
+            is_upgraded: AtomicBool::new(false),
             #[cfg(test)]
             is_debug_force_exit_mode: false,
         }
     }
 
     pub(in crate::skk) fn setup(&mut self, config: &Config) -> Result<(), SkkError> {
+        Logger::setup(config)?;
         if config.is_google_cache_enabled {
             GoogleCache::setup_use_rwlock_internally(&config.google_cache_full_path)?;
         }
@@ -252,19 +553,237 @@ impl Yaskkserv2 {
         Ok(())
     }
 
-    pub(in crate::skk) fn run(&self) {
+    /// dictionary を reload する
+    ///
+    /// 失敗した場合は使用中の dictionary をそのまま使い続ける。
+    pub(in crate::skk) fn reload_dictionary(&self) -> Result<(), SkkError> {
+        match self.server.dictionary.reload() {
+            Ok(()) => {
+                Self::log_info(&format!(
+                    "dictionary reloaded {}",
+                    self.server.config.dictionary_full_path
+                ));
+                Ok(())
+            }
+            Err(e) => {
+                Self::log_error(&format!(
+                    "dictionary reload failed {}  error={e}",
+                    self.server.config.dictionary_full_path
+                ));
+                Err(e)
+            }
+        }
+    }
+
+    /// SIGHUP で config file を読み直すために、 command line で指定された config を設定する
+    pub(in crate::skk) fn set_command_line_config(&mut self, command_line_config: Config) {
+        self.command_line_config = Some(command_line_config);
+    }
+
+    /// config file を読み直して実行中に変更できる設定を反映する
+    ///
+    /// 反映するのは Google Japanese Input と Google Suggest の設定、 `idle_timeout_seconds`
+    /// と `max_server_completions` のみ。 config file の読み込みに失敗した場合は使用中の
+    /// config をそのまま使い続ける。
+    pub(in crate::skk) fn reload_config(&self) -> Result<(), SkkError> {
+        let Some(command_line_config) = &self.command_line_config else {
+            return Ok(());
+        };
+        let mut config_file = config_file::Yaskkserv2ConfigFile::new(command_line_config);
+        if let Err(e) = config_file.read() {
+            Self::log_error(&format!(
+                "config reload failed {}  error={e}",
+                command_line_config.full_path
+            ));
+            return Err(e);
+        }
+        let config = config_file.get_config();
+        if !self.server.dictionary.update_config(&config) {
+            Self::log_warn("google-japanese-input disable can not be changed without restart");
+        }
+        Self::log_info(&format!("config reloaded {}", config.full_path));
+        Ok(())
+    }
+
+    /// 起動してからの統計を名前と値の組で返す
+    fn get_statistics(&self, connections: usize) -> Vec<(&'static str, u64)> {
+        let statistics = &self.server.dictionary.statistics;
+        vec![
+            ("uptime_seconds", statistics.started.elapsed().as_secs()),
+            ("connections", connections as u64),
+            (
+                "accepted_connections",
+                statistics.accepted_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "rejected_connections_access_control",
+                statistics
+                    .rejected_connections_access_control
+                    .load(Ordering::Relaxed),
+            ),
+            (
+                "rejected_connections_max_connections",
+                statistics
+                    .rejected_connections_max_connections
+                    .load(Ordering::Relaxed),
+            ),
+            ("requests", statistics.get_requests()),
+            (
+                "dictionary_hits",
+                statistics.dictionary_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "dictionary_misses",
+                statistics.dictionary_misses.load(Ordering::Relaxed),
+            ),
+            (
+                "google_requests",
+                statistics.google_requests.load(Ordering::Relaxed),
+            ),
+            (
+                "google_blocked",
+                statistics.google_blocked.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// 起動してからの統計を log に出力する
+    fn log_statistics(&self, connections: usize) {
+        let statistics = self
+            .get_statistics(connections)
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self::log_info(&format!("statistics {statistics}"));
+    }
+
+    pub(in crate::skk) fn run(&mut self) {
         Self::log_info(&format!(
             "version {} (port={})",
             PKG_VERSION, self.server.config.port
         ));
+        match Listener::bind_all(&self.server.config) {
+            Ok(listeners) => self.run_listeners(
+                &listeners,
+                #[cfg(unix)]
+                None,
+            ),
+            Err(e) => {
+                let message = format!("bind failed {e}");
+                Self::log_error(&message);
+                Self::print_warning(&message);
+            }
+        }
+    }
+
+    /// systemd の socket activation で起動されたかどうかを返す
+    #[cfg(unix)]
+    pub(in crate::skk) fn is_socket_activation() -> bool {
+        Listener::get_socket_activation_fds().is_some()
+    }
+
+    /// bind せず、 systemd から継承した listening socket で server を実行する
+    #[cfg(unix)]
+    pub(in crate::skk) fn run_socket_activation(&mut self) {
+        Self::log_info(&format!("version {PKG_VERSION} (socket activation)"));
+        match Listener::from_socket_activation() {
+            Ok(listeners) => self.run_listeners(
+                &listeners,
+                #[cfg(unix)]
+                None,
+            ),
+            Err(e) => {
+                let message = format!("socket activation failed {e}");
+                Self::log_error(&message);
+                Self::print_warning(&message);
+            }
+        }
+    }
+
+    /// stdin と stdout で 1 つの client との session を処理する
+    ///
+    /// inetd や ssh などから起動するためのもの。 stdout は protocol で使うので、 error は
+    /// `print_warning()` せず log にのみ出力する。
+    pub(in crate::skk) fn run_stdio(&mut self) {
+        Self::log_info(&format!("version {PKG_VERSION} (stdio)"));
+        if self
+            .server
+            .config
+            .hostname_and_ip_address_for_protocol_3
+            .is_empty()
+        {
+            self.server.config.hostname_and_ip_address_for_protocol_3 =
+                Listener::get_hostname_and_ip_address_for_protocol_3(&[]);
+        }
+        if let Err(e) = self.run_stdio_loop(StdioStream::new()) {
+            Self::log_error(&format!("run_stdio_loop() failed {e}"));
+        }
+    }
+
+    /// `stream` から request を読み、 client が切断するまで処理する
+    ///
+    /// Google Japanese Input は worker thread を使わずにその場で呼ぶ。
+    fn run_stdio_loop<S: Read + Write>(&self, stream: S) -> Result<(), SkkError> {
+        let mut buffer_stream = BufReader::new(stream);
+        let mut dictionary_file = DictionaryFile::new(
+            File::open(&self.server.config.dictionary_full_path)?,
+            INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH,
+        );
+        let mut buffer: Vec<u8> = Vec::new();
+        loop {
+            let size = buffer_stream.read_until_skk_server(&mut buffer)?;
+            if size == 0 {
+                return Ok(());
+            }
+            let mut access_log_entry = AccessLogEntry::new(STDIO_PEER);
+            match self.handle_client_buffer(
+                buffer_stream.get_mut(),
+                &mut dictionary_file,
+                &mut buffer,
+                size,
+                &mut access_log_entry,
+            ) {
+                HandleClientResult::Continue => {}
+                HandleClientResult::Google(mut google_buffer) => {
+                    self.server
+                        .dictionary
+                        .statistics
+                        .google_requests
+                        .fetch_add(1, Ordering::Relaxed);
+                    let response = self.server.handle_client_protocol_1_google(
+                        &mut dictionary_file,
+                        &mut google_buffer,
+                        &mut access_log_entry,
+                    );
+                    buffer_stream
+                        .get_mut()
+                        .write_all_flush_ignore_error(&response);
+                }
+                HandleClientResult::Exit => return Ok(()),
+            }
+            buffer.clear();
+        }
+    }
+
+    /// `handover` は upgrade 元の process から継承したもので、通常の起動では `None` となる
+    fn run_listeners(
+        &mut self,
+        listeners: &[Listener],
+        #[cfg(unix)] handover: Option<UpgradeHandover>,
+    ) {
         #[cfg(test)]
-        if let Err(e) = self.run_loop(0) {
+        if let Err(e) = self.run_loop(listeners, handover, 0) {
             let message = format!("run_loop() failed {e}");
             Self::log_error(&message);
             Self::print_warning(&message);
         }
         #[cfg(not(test))]
-        if let Err(e) = self.run_loop() {
+        if let Err(e) = self.run_loop(
+            listeners,
+            #[cfg(unix)]
+            handover,
+        ) {
             let message = format!("run_loop() failed {e}");
             Self::log_error(&message);
             Self::print_warning(&message);
@@ -311,15 +830,284 @@ impl Yaskkserv2 {
         panic!("illegal sockets slice");
     }
 
+    /// `listeners` で poll loop を実行する
+    ///
+    /// `hostname_and_ip_address_for_protocol_3` が指定されていない場合は、 `listeners` の
+    /// address から作成する。
+    ///
+    /// `handover` がある場合は、継承した control socket と metrics の listener を使い、
+    /// poll loop を開始する直前に upgrade 元の process へ ready を通知する。
+    fn run_loop(
+        &mut self,
+        listeners: &[Listener],
+        #[cfg(unix)] handover: Option<UpgradeHandover>,
+        #[cfg(test)] take_count_for_test: usize,
+    ) -> Result<(), SkkError> {
+        if self
+            .server
+            .config
+            .hostname_and_ip_address_for_protocol_3
+            .is_empty()
+        {
+            self.server.config.hostname_and_ip_address_for_protocol_3 =
+                Listener::get_hostname_and_ip_address_for_protocol_3(listeners);
+        }
+        #[cfg(unix)]
+        let (inherited_control_listener, inherited_metrics_listener, mut ready) =
+            handover.map_or((None, None, None), |handover| {
+                (
+                    handover.control_listener,
+                    handover.metrics_listener,
+                    Some(handover.ready),
+                )
+            });
+        #[cfg(unix)]
+        let control_listener = if inherited_control_listener.is_some() {
+            inherited_control_listener
+        } else if self.server.config.control_socket.is_empty() {
+            None
+        } else {
+            Some(Listener::bind_unix(
+                &self.server.config.control_socket,
+                CONTROL_SOCKET_MODE,
+            )?)
+        };
+        #[cfg(not(unix))]
+        let control_listener: Option<Listener> = None;
+        #[cfg(not(unix))]
+        let inherited_metrics_listener: Option<std::net::TcpListener> = None;
+        let metrics_listener = if inherited_metrics_listener.is_some() {
+            inherited_metrics_listener
+        } else if self.server.config.metrics_address.is_empty() {
+            None
+        } else {
+            let listener = std::net::TcpListener::bind(&self.server.config.metrics_address)?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        };
+        // 失敗した場合も unix domain socket を削除するため、 `?` で return しない
+        //
+        // upgrade の場合、 user と group は upgrade 元の process で切り替え済み
+        #[cfg(unix)]
+        let result = if ready.is_some() {
+            Ok(())
+        } else {
+            Self::drop_privileges(&self.server.config)
+        };
+        #[cfg(not(unix))]
+        let result = Ok(());
+        let result = result.and_then(|()| Self::apply_sandbox(&self.server.config));
+        #[cfg(unix)]
+        let result = result.and_then(|()| match ready.take() {
+            Some(mut ready) => Ok(ready.write_all(b"1")?),
+            None => Ok(()),
+        });
+        let result = result.and_then(|()| {
+            self.run_loop_listeners(
+                listeners,
+                control_listener.as_ref(),
+                metrics_listener.as_ref(),
+                #[cfg(test)]
+                take_count_for_test,
+            )
+        });
+        // upgrade した場合、 unix domain socket と Google Japanese Input cache は新しい
+        // process が使い続ける
+        if self.is_upgraded.load(Ordering::SeqCst) {
+            return result;
+        }
+        for listener in listeners.iter().chain(&control_listener) {
+            listener.remove_unix_socket();
+        }
+        let config = self.server.dictionary.get_config();
+        if config.is_google_cache_enabled {
+            if let Err(e) = GoogleCache::flush(&config.google_cache_full_path) {
+                Self::log_error(&format!("google cache flush failed error={e}"));
+            }
+        }
+        result
+    }
+
+    /// `worker_threads` 個の thread でそれぞれ poll loop を実行する。
+    ///
+    /// listener は `try_clone()` したものを各 poll loop に登録する。 connection が来ると全ての
+    /// poll loop に通知されるが、 `accept()` できるのは 1 つだけで、他は `WouldBlock` となる。
+    /// `OnMemory` は `Server` ごと全 thread で共有し、 `DictionaryFile` は thread ごとに open
+    /// する。
+    ///
+    /// Google Japanese Input は network を介するため時間がかかる。 poll loop で呼ぶと他の
+    /// client が待たされるので `GoogleWorker` の thread で呼び、結果は `Waker` で poll loop に
+    /// 通知してから送信する。
+    #[allow(clippy::too_many_lines)]
+    fn run_loop_listeners(
+        &self,
+        listeners: &[Listener],
+        control_listener: Option<&Listener>,
+        metrics_listener: Option<&std::net::TcpListener>,
+        #[cfg(test)] take_count_for_test: usize,
+    ) -> Result<(), SkkError> {
+        #[allow(clippy::cast_sign_loss)]
+        let worker_threads = std::cmp::max(self.server.config.worker_threads, 1) as usize;
+        let mut polls = Vec::new();
+        let mut wakers = Vec::new();
+        for _ in 0..worker_threads {
+            let poll = Poll::new()?;
+            wakers.push(Waker::new(poll.registry(), WAKER)?);
+            polls.push(poll);
+        }
+        let control_poll = control_listener.map(|_| Poll::new()).transpose()?;
+        let metrics_poll = metrics_listener.map(|_| Poll::new()).transpose()?;
+        let mut service_wakers = Vec::new();
+        for poll in control_poll.iter().chain(&metrics_poll) {
+            service_wakers.push(Waker::new(poll.registry(), WAKER)?);
+        }
+        let shared = RunLoopShared {
+            wakers,
+            service_wakers,
+            connections: AtomicUsize::new(0),
+            next_socket_serial: AtomicU64::new(0),
+            connection_list: Mutex::new(BTreeMap::new()),
+            evictions: (0..worker_threads)
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
+            is_exit: AtomicBool::new(false),
+            is_shutdown: AtomicBool::new(false),
+            is_draining: AtomicBool::new(false),
+            #[cfg(unix)]
+            is_upgrading: AtomicBool::new(false),
+            #[cfg(unix)]
+            upgrade_fds: UpgradeFds::new(listeners, control_listener, metrics_listener),
+            #[cfg(test)]
+            take_index_for_test: AtomicUsize::new(0),
+        };
+        let (google_job_sender, google_job_receiver) = mpsc::channel();
+        let google_job_receiver = Mutex::new(google_job_receiver);
+        let mut google_result_senders = Vec::new();
+        let mut google_result_receivers = Vec::new();
+        for _ in 0..worker_threads {
+            let (google_result_sender, google_result_receiver) = mpsc::channel();
+            google_result_senders.push(google_result_sender);
+            google_result_receivers.push(google_result_receiver);
+        }
+        let result = std::thread::scope(|scope| {
+            #[cfg(unix)]
+            if let (Some(control_poll), Some(control_listener)) = (control_poll, control_listener) {
+                let shared = &shared;
+                std::thread::Builder::new()
+                    .name(String::from("control"))
+                    .spawn_scoped(scope, move || {
+                        if let Err(e) = self.run_control(control_poll, control_listener, shared) {
+                            Self::log_error(&format!("run_control() failed {e}"));
+                        }
+                    })?;
+            }
+            if let (Some(metrics_poll), Some(metrics_listener)) = (metrics_poll, metrics_listener) {
+                let shared = &shared;
+                std::thread::Builder::new()
+                    .name(String::from("metrics"))
+                    .spawn_scoped(scope, move || {
+                        if let Err(e) = self.run_metrics(metrics_poll, metrics_listener, shared) {
+                            Self::log_error(&format!("run_metrics() failed {e}"));
+                        }
+                    })?;
+            }
+            #[cfg(unix)]
+            let signals_handle = self.spawn_signal_handler(scope, &shared)?;
+            if self.server.config.google_timing != GoogleTiming::Disable {
+                for _ in 0..GOOGLE_WORKER_THREADS {
+                    let google_worker = GoogleWorker::new(
+                        &self.server,
+                        &google_job_receiver,
+                        google_result_senders.clone(),
+                        &shared.wakers,
+                    );
+                    std::thread::Builder::new()
+                        .name(String::from("google_worker"))
+                        .spawn_scoped(scope, move || google_worker.run())?;
+                }
+            }
+            drop(google_result_senders);
+            let mut thread_handles = Vec::new();
+            for (loop_index, (poll, google_result_receiver)) in
+                polls.into_iter().zip(google_result_receivers).enumerate()
+            {
+                let shared = &shared;
+                let google_job_sender = google_job_sender.clone();
+                let thread_handle = listeners
+                    .iter()
+                    .map(Listener::try_clone_mio)
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|mio_listeners| {
+                        std::thread::Builder::new()
+                            .name(String::from("run_loop"))
+                            .spawn_scoped(scope, move || {
+                                let result = self.run_loop_thread(
+                                    loop_index,
+                                    poll,
+                                    mio_listeners,
+                                    &google_job_sender,
+                                    &google_result_receiver,
+                                    shared,
+                                    #[cfg(test)]
+                                    take_count_for_test,
+                                );
+                                // 1 つの poll loop が終了したら、全ての poll loop を終了させる
+                                //
+                                // shutdown と drain の間は、他の poll loop が response を送信し
+                                // 終えるのを待つことに注意
+                                if result.is_err()
+                                    || (!shared.is_shutdown.load(Ordering::SeqCst)
+                                        && !shared.is_draining.load(Ordering::SeqCst))
+                                {
+                                    shared.exit();
+                                }
+                                result
+                            })
+                    });
+                match thread_handle {
+                    Ok(thread_handle) => thread_handles.push(thread_handle),
+                    Err(e) => {
+                        shared.exit();
+                        #[cfg(unix)]
+                        signals_handle.close();
+                        return Err(SkkError::Io(e));
+                    }
+                }
+            }
+            // worker は全ての job sender が drop されると終了することに注意
+            drop(google_job_sender);
+            let mut result = Ok(());
+            for thread_handle in thread_handles {
+                let thread_result = thread_handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                if result.is_ok() {
+                    result = thread_result;
+                }
+            }
+            #[cfg(unix)]
+            signals_handle.close();
+            result
+        });
+        result
+    }
+
     /// sockets に `HashMap` ではなく `Vec` を使用する理由は、常に実行される `sockets.get_mut()`
     /// の速度を重視するため。
     /// なお、 `Vec` は `HashMap` に比べて empty index を探す必要がある分だけ `insert()` 相当の
     /// 処理が少しだけ高くつくが、最悪のケースでもそもそも `insert()` 相当処理の実行頻度は
     /// 低いので問題にならない。
-    fn run_loop(&self, #[cfg(test)] take_count_for_test: usize) -> Result<(), SkkError> {
-        const LISTENER: Token = Token(MAX_CONNECTION);
-        #[cfg(test)]
-        let mut take_index_for_test = 0;
+    #[allow(clippy::too_many_arguments, clippy::too_many_lines)]
+    fn run_loop_thread(
+        &self,
+        loop_index: usize,
+        mut poll: Poll,
+        mut listeners: Vec<MioListener>,
+        google_job_sender: &mpsc::Sender<GoogleWorkerJob>,
+        google_result_receiver: &mpsc::Receiver<GoogleWorkerResult>,
+        shared: &RunLoopShared,
+        #[cfg(test)] take_count_for_test: usize,
+    ) -> Result<(), SkkError> {
         let mut sockets: Vec<Option<MioSocket>> = Vec::new();
         for _ in 0..self.server.config.max_connections {
             sockets.push(None);
@@ -327,54 +1115,156 @@ impl Yaskkserv2 {
         let sockets_length = sockets.len();
         let mut sockets_some_count = 0;
         let mut next_socket_index = 0;
-        let mut poll = Poll::new()?;
-        let mut listener = TcpListener::bind(SocketAddr::new(
-            self.server.config.listen_address.parse().unwrap(),
-            self.server.config.port.parse().unwrap(),
-        ))?;
-        poll.registry()
-            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        for (index, listener) in listeners.iter_mut().enumerate() {
+            poll.registry().register(
+                listener,
+                Token(LISTENER_TOKEN_BASE + index),
+                Interest::READABLE,
+            )?;
+        }
         let mut events = Events::with_capacity(MAX_CONNECTION);
         let mut dictionary_file = DictionaryFile::new(
             File::open(&self.server.config.dictionary_full_path)?,
             INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH,
         );
         let mut buffer: Vec<u8> = Vec::new();
+        let mut last_idle_check = Instant::now();
+        let mut is_listening = true;
+        let mut shutdown_deadline: Option<Instant> = None;
+        #[cfg_attr(not(unix), allow(unused_mut))]
+        let mut drain_deadline: Option<Instant> = None;
         loop {
-            if let Err(e) = poll.poll(&mut events, None) {
+            // idle timeout は SIGHUP で変更されることに注意
+            let idle_timeout_seconds = self.server.dictionary.get_config().idle_timeout_seconds;
+            let idle_timeout =
+                (idle_timeout_seconds > 0).then(|| Duration::from_secs(idle_timeout_seconds));
+            let poll_timeout = if shutdown_deadline.is_some() {
+                Some(SHUTDOWN_CHECK_INTERVAL)
+            } else if drain_deadline.is_some() {
+                Some(IDLE_TIMEOUT_CHECK_INTERVAL)
+            } else {
+                idle_timeout.map(|_| IDLE_TIMEOUT_CHECK_INTERVAL)
+            };
+            if let Err(e) = poll.poll(&mut events, poll_timeout) {
                 let message = &format!("poll failed {e}");
                 Self::log_error(message);
                 Self::print_warning(message);
             }
+            if let Some(idle_timeout) = idle_timeout {
+                if last_idle_check.elapsed() >= IDLE_TIMEOUT_CHECK_INTERVAL {
+                    last_idle_check = Instant::now();
+                    Self::close_idle_sockets(
+                        &poll,
+                        &mut sockets,
+                        &mut sockets_some_count,
+                        &mut next_socket_index,
+                        shared,
+                        idle_timeout,
+                    )?;
+                }
+            }
             for event in &events {
                 match event.token() {
-                    LISTENER => loop {
+                    Token(index) if index >= LISTENER_TOKEN_BASE => loop {
                         match self.run_loop_listener(
+                            loop_index,
                             &mut next_socket_index,
                             &mut sockets,
                             &mut sockets_some_count,
                             &poll,
-                            &listener,
+                            &listeners[index - LISTENER_TOKEN_BASE],
                             sockets_length,
-                            #[cfg(test)]
-                            &mut take_index_for_test,
+                            shared,
                         ) {
                             Ok(RunLoopListenerResult::Break) => break,
                             Ok(RunLoopListenerResult::Nop) => {}
                             Err(e) => return Err(e),
                         }
                     },
+                    WAKER => {
+                        if shared.is_exit.load(Ordering::SeqCst) {
+                            return Ok(());
+                        }
+                        let evictions =
+                            std::mem::take(&mut *shared.evictions[loop_index].lock().unwrap());
+                        for (token, serial) in evictions {
+                            // 依頼の後に閉じて別の connection が token を再利用している場合が
+                            // あるので serial を確認する
+                            if sockets[usize::from(token)]
+                                .as_ref()
+                                .is_some_and(|socket| socket.serial == serial)
+                            {
+                                Self::close_socket(
+                                    &poll,
+                                    &mut sockets,
+                                    &mut sockets_some_count,
+                                    &mut next_socket_index,
+                                    shared,
+                                    token,
+                                    true,
+                                )?;
+                            }
+                        }
+                        while let Ok(google_result) = google_result_receiver.try_recv() {
+                            let token = google_result.token;
+                            match sockets[usize::from(token)].as_mut() {
+                                Some(socket) if socket.serial == google_result.serial => {
+                                    socket.is_waiting_google = false;
+                                    socket
+                                        .output_buffer
+                                        .extend_from_slice(&google_result.response);
+                                }
+                                _ => continue,
+                            }
+                            // 結果待ちの間に届いた request は読まれていないので、ここで読む
+                            // ことに注意
+                            match self.run_loop_token(
+                                loop_index,
+                                &mut next_socket_index,
+                                &mut sockets,
+                                &mut sockets_some_count,
+                                &mut buffer,
+                                &mut dictionary_file,
+                                google_job_sender,
+                                &poll,
+                                token,
+                                shared,
+                                #[cfg(test)]
+                                take_count_for_test,
+                            ) {
+                                Ok(RunLoopTokenResult::Return) => return Ok(()),
+                                Ok(RunLoopTokenResult::Nop) => {}
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        let is_shutdown = shared.is_shutdown.load(Ordering::SeqCst);
+                        let is_draining = shared.is_draining.load(Ordering::SeqCst);
+                        if is_listening && (is_shutdown || is_draining) {
+                            for listener in &mut listeners {
+                                poll.registry().deregister(listener)?;
+                            }
+                            is_listening = false;
+                        }
+                        if shutdown_deadline.is_none() && is_shutdown {
+                            shutdown_deadline = Some(Instant::now() + SHUTDOWN_TIMEOUT);
+                        }
+                        #[cfg(unix)]
+                        if drain_deadline.is_none() && is_draining {
+                            drain_deadline = Some(Instant::now() + UPGRADE_DRAIN_TIMEOUT);
+                        }
+                    }
                     token => {
                         match self.run_loop_token(
+                            loop_index,
                             &mut next_socket_index,
                             &mut sockets,
                             &mut sockets_some_count,
                             &mut buffer,
                             &mut dictionary_file,
+                            google_job_sender,
                             &poll,
                             token,
-                            #[cfg(test)]
-                            take_index_for_test,
+                            shared,
                             #[cfg(test)]
                             take_count_for_test,
                         ) {
@@ -385,37 +1275,138 @@ impl Yaskkserv2 {
                     }
                 }
             }
+            // drain 中は client が切断するまで処理を続け、 timeout したら shutdown する
+            if let Some(drain_deadline) = drain_deadline {
+                if sockets_some_count == 0 {
+                    return Ok(());
+                }
+                if !shared.is_shutdown.load(Ordering::SeqCst) && Instant::now() >= drain_deadline {
+                    Self::log_info(&format!(
+                        "upgrade drain timeout, {sockets_some_count} connections will be closed"
+                    ));
+                    shared.shutdown();
+                }
+            }
+            if let Some(shutdown_deadline) = shutdown_deadline {
+                Self::close_finished_sockets(
+                    &poll,
+                    &mut sockets,
+                    &mut sockets_some_count,
+                    &mut next_socket_index,
+                    shared,
+                )?;
+                if sockets_some_count == 0 {
+                    return Ok(());
+                }
+                if Instant::now() >= shutdown_deadline {
+                    Self::log_error(&format!(
+                        "shutdown timeout, {sockets_some_count} connections closed"
+                    ));
+                    return Ok(());
+                }
+            }
         }
     }
 
     #[allow(clippy::too_many_arguments)]
     fn run_loop_listener(
         &self,
+        loop_index: usize,
         next_socket_index: &mut usize,
         sockets: &mut [Option<MioSocket>],
         sockets_some_count: &mut usize,
         poll: &Poll,
-        listener: &TcpListener,
+        listener: &MioListener,
         sockets_length: usize,
-        #[cfg(test)] take_index_for_test: &mut usize,
+        shared: &RunLoopShared,
     ) -> Result<RunLoopListenerResult, SkkError> {
         match listener.accept() {
-            Ok((mut socket, _)) => {
+            Ok(mut socket) => {
+                let peer_ip_address = socket.peer_ip_address();
+                #[cfg(test)]
+                let peer_ip_address = if self.server.config.is_debug_peer_address_error
+                    && matches!(peer_ip_address, Ok(Some(_)))
+                {
+                    Err(std::io::Error::from(std::io::ErrorKind::NotConnected))
+                } else {
+                    peer_ip_address
+                };
+                let rejected_message = match peer_ip_address {
+                    Ok(Some(ip_address)) if !self.is_allowed_ip_address(ip_address) => {
+                        Some(format!("rejected connection from {ip_address}"))
+                    }
+                    Ok(_) => None,
+                    // allow-from と deny-from で判定できないので拒否する
+                    Err(e) => Some(format!(
+                        "rejected connection from unknown address  error={e}"
+                    )),
+                };
+                if let Some(rejected_message) = rejected_message {
+                    self.server
+                        .dictionary
+                        .statistics
+                        .rejected_connections_access_control
+                        .fetch_add(1, Ordering::Relaxed);
+                    Self::log_error(&rejected_message);
+                    return Ok(RunLoopListenerResult::Nop);
+                }
                 #[allow(clippy::cast_sign_loss)]
-                if *sockets_some_count >= self.server.config.max_connections as usize {
+                let max_connections = self.server.config.max_connections as usize;
+                if shared.connections.fetch_add(1, Ordering::SeqCst) >= max_connections
+                    && !Self::evict_oldest_idle_socket(
+                        loop_index,
+                        poll,
+                        sockets,
+                        sockets_some_count,
+                        next_socket_index,
+                        shared,
+                    )?
+                {
+                    shared.connections.fetch_sub(1, Ordering::SeqCst);
+                    self.server
+                        .dictionary
+                        .statistics
+                        .rejected_connections_max_connections
+                        .fetch_add(1, Ordering::Relaxed);
+                    Self::log_error(&format!(
+                        "max connections reached, connection rejected  addr={}",
+                        socket
+                            .peer_addr()
+                            .unwrap_or_else(|e| format!("unknown ({})", e.kind()))
+                    ));
                     return Ok(RunLoopListenerResult::Break);
                 }
+                // test の終了判定で connections より先に参照されないよう、 connections の後で
+                // 加算することに注意
                 #[cfg(test)]
-                {
-                    *take_index_for_test += 1;
-                }
+                shared.take_index_for_test.fetch_add(1, Ordering::SeqCst);
+                self.server
+                    .dictionary
+                    .statistics
+                    .accepted_connections
+                    .fetch_add(1, Ordering::Relaxed);
                 let token = Token(*next_socket_index);
                 poll.registry()
                     .register(&mut socket, token, Interest::READABLE)?;
-                sockets[usize::from(token)] = Some(MioSocket::new(socket));
+                let serial = shared.next_socket_serial.fetch_add(1, Ordering::Relaxed);
+                let address = socket
+                    .peer_addr()
+                    .unwrap_or_else(|e| format!("unknown ({})", e.kind()));
+                shared.connection_list.lock().unwrap().insert(
+                    serial,
+                    ConnectionInformation {
+                        address: address.clone(),
+                        connected: Instant::now(),
+                        loop_index,
+                        token,
+                        last_active: Instant::now(),
+                        is_waiting_google: false,
+                        is_evicting: false,
+                    },
+                );
+                sockets[usize::from(token)] = Some(MioSocket::new(socket, serial, address));
                 *sockets_some_count += 1;
-                #[allow(clippy::cast_sign_loss)]
-                if *sockets_some_count < self.server.config.max_connections as usize {
+                if *sockets_some_count < max_connections {
                     *next_socket_index =
                         Self::get_empty_sockets_index(sockets, sockets_length, *next_socket_index);
                 }
@@ -428,17 +1419,193 @@ impl Yaskkserv2 {
         }
     }
 
+    /// `token` の connection を閉じ、 sockets から取り除く
+    #[allow(clippy::too_many_arguments)]
+    fn close_socket(
+        poll: &Poll,
+        sockets: &mut [Option<MioSocket>],
+        sockets_some_count: &mut usize,
+        next_socket_index: &mut usize,
+        shared: &RunLoopShared,
+        token: Token,
+        is_shutdown: bool,
+    ) -> Result<(), SkkError> {
+        let Some(mut socket) = sockets[usize::from(token)].take() else {
+            return Ok(());
+        };
+        poll.registry().deregister(socket.buffer_stream.get_mut())?;
+        if is_shutdown {
+            if let Err(e) = &socket.buffer_stream.get_mut().shutdown(Shutdown::Both) {
+                Self::log_error(&format!("shutdown error={e}"));
+            }
+        }
+        *sockets_some_count -= 1;
+        shared.connections.fetch_sub(1, Ordering::SeqCst);
+        shared
+            .connection_list
+            .lock()
+            .unwrap()
+            .remove(&socket.serial);
+        *next_socket_index = usize::from(token);
+        Ok(())
+    }
+
+    /// `idle_timeout` 以上 request の無い connection を閉じる
+    ///
+    /// Google Japanese Input の結果待ちの connection は閉じない。
+    fn close_idle_sockets(
+        poll: &Poll,
+        sockets: &mut [Option<MioSocket>],
+        sockets_some_count: &mut usize,
+        next_socket_index: &mut usize,
+        shared: &RunLoopShared,
+        idle_timeout: Duration,
+    ) -> Result<(), SkkError> {
+        for index in 0..sockets.len() {
+            let is_idle = sockets[index].as_ref().is_some_and(|socket| {
+                !socket.is_waiting_google && socket.last_active.elapsed() >= idle_timeout
+            });
+            if is_idle {
+                Self::close_socket(
+                    poll,
+                    sockets,
+                    sockets_some_count,
+                    next_socket_index,
+                    shared,
+                    Token(index),
+                    true,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// shutdown 中に、 response を送信し終えた connection を閉じる
+    ///
+    /// Google Japanese Input の結果待ちと、送信待ちの response が残っている connection は
+    /// 閉じない。
+    fn close_finished_sockets(
+        poll: &Poll,
+        sockets: &mut [Option<MioSocket>],
+        sockets_some_count: &mut usize,
+        next_socket_index: &mut usize,
+        shared: &RunLoopShared,
+    ) -> Result<(), SkkError> {
+        for index in 0..sockets.len() {
+            let is_finished = sockets[index]
+                .as_ref()
+                .is_some_and(|socket| !socket.is_waiting_google && !socket.is_write_pending());
+            if is_finished {
+                Self::close_socket(
+                    poll,
+                    sockets,
+                    sockets_some_count,
+                    next_socket_index,
+                    shared,
+                    Token(index),
+                    true,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// max connections に達した場合に、全ての poll loop の中で最も長く request の無い
+    /// connection を閉じる
+    ///
+    /// Google Japanese Input の結果待ちの connection は閉じない。他の poll loop の connection は
+    /// その poll loop に閉じるよう依頼するので、閉じ終わるまでの間は connections が一時的に
+    /// max connections を越えることに注意。この poll loop の sockets に空きが無い場合は、
+    /// この poll loop の connection だけから選ぶ。閉じられる connection が無い場合は false を
+    /// 返す。
     #[allow(clippy::too_many_arguments)]
+    fn evict_oldest_idle_socket(
+        loop_index: usize,
+        poll: &Poll,
+        sockets: &mut [Option<MioSocket>],
+        sockets_some_count: &mut usize,
+        next_socket_index: &mut usize,
+        shared: &RunLoopShared,
+    ) -> Result<bool, SkkError> {
+        let is_local_only = *sockets_some_count >= sockets.len();
+        let oldest = {
+            let mut connection_list = shared.connection_list.lock().unwrap();
+            connection_list
+                .iter_mut()
+                .filter(|(_, information)| {
+                    !information.is_waiting_google
+                        && !information.is_evicting
+                        && (!is_local_only || information.loop_index == loop_index)
+                })
+                .min_by_key(|(_, information)| information.last_active)
+                .map(|(serial, information)| {
+                    information.is_evicting = true;
+                    (
+                        *serial,
+                        information.loop_index,
+                        information.token,
+                        information.address.clone(),
+                    )
+                })
+        };
+        let Some((serial, oldest_loop_index, oldest_token, address)) = oldest else {
+            return Ok(false);
+        };
+        Self::log_info(&format!(
+            "max connections reached, idle connection closed  addr={address}"
+        ));
+        if oldest_loop_index == loop_index {
+            Self::close_socket(
+                poll,
+                sockets,
+                sockets_some_count,
+                next_socket_index,
+                shared,
+                oldest_token,
+                true,
+            )?;
+        } else {
+            shared.evictions[oldest_loop_index]
+                .lock()
+                .unwrap()
+                .push((oldest_token, serial));
+            if let Err(e) = shared.wakers[oldest_loop_index].wake() {
+                Self::log_error(&format!("eviction wake failed {e}"));
+            }
+        }
+        Ok(true)
+    }
+
+    /// `deny-from` に含まれず、 `allow-from` が指定されている場合はそれに含まれるかを返す
+    fn is_allowed_ip_address(&self, ip_address: IpAddr) -> bool {
+        let config = &self.server.config;
+        if config
+            .deny_from
+            .iter()
+            .any(|cidr| cidr.contains(ip_address))
+        {
+            return false;
+        }
+        config.allow_from.is_empty()
+            || config
+                .allow_from
+                .iter()
+                .any(|cidr| cidr.contains(ip_address))
+    }
+
+    #[allow(clippy::too_many_arguments, clippy::too_many_lines)]
     fn run_loop_token(
         &self,
+        loop_index: usize,
         next_socket_index: &mut usize,
         sockets: &mut [Option<MioSocket>],
         sockets_some_count: &mut usize,
         buffer: &mut Vec<u8>,
         dictionary_file: &mut DictionaryFile,
+        google_job_sender: &mpsc::Sender<GoogleWorkerJob>,
         poll: &Poll,
         token: Token,
-        #[cfg(test)] take_index_for_test: usize,
+        shared: &RunLoopShared,
         #[cfg(test)] take_count_for_test: usize,
     ) -> Result<RunLoopTokenResult, SkkError> {
         #[allow(clippy::get_unwrap)]
@@ -448,29 +1615,91 @@ impl Yaskkserv2 {
             Self::print_warning(message);
             return Ok(RunLoopTokenResult::Return);
         };
-        let mut is_shutdown = false;
-        match self.read_until_skk_server(socket, buffer, dictionary_file, &mut is_shutdown) {
-            HandleClientResult::Continue => {}
-            HandleClientResult::Exit => {
-                poll.registry().deregister(socket.buffer_stream.get_mut())?;
-                if is_shutdown {
-                    if let Err(e) = &socket.buffer_stream.get_mut().shutdown(Shutdown::Both) {
-                        Self::log_error(&format!("shutdown error={e}"));
+        if socket.is_waiting_google {
+            return Ok(RunLoopTokenResult::Nop);
+        }
+        socket.last_active = Instant::now();
+        let mut is_shutdown = if let Err(e) = socket.write_output_buffer() {
+            Self::log_error(&format!("write_output_buffer() error={e}"));
+            true
+        } else {
+            false
+        };
+        let mut is_exit = is_shutdown;
+        let mut is_would_block = false;
+        // event は edge trigger なので、 `WouldBlock` になるまで request を読んで処理する
+        //
+        // Google Japanese Input の結果待ちの間と、送信待ちの response が残っている間は、次の
+        // request を読まない。残りは結果を送信した後と、送信が終わった後で処理する。
+        while !is_exit && !is_would_block && !socket.is_write_pending() {
+            let mut access_log_entry = AccessLogEntry::new(&socket.peer);
+            match self.read_until_skk_server(
+                socket,
+                buffer,
+                dictionary_file,
+                &mut is_shutdown,
+                &mut is_would_block,
+                &mut access_log_entry,
+            ) {
+                HandleClientResult::Continue => {}
+                HandleClientResult::Google(google_buffer) => {
+                    let job = GoogleWorkerJob {
+                        loop_index,
+                        token,
+                        serial: socket.serial,
+                        buffer: google_buffer,
+                        access_log_entry,
+                    };
+                    if google_job_sender.send(job).is_ok() {
+                        self.server
+                            .dictionary
+                            .statistics
+                            .google_requests
+                            .fetch_add(1, Ordering::Relaxed);
+                        socket.is_waiting_google = true;
+                    } else {
+                        Self::log_error("google worker send failed");
+                        let _ignore_error = socket.output_buffer.write_error_flush();
                     }
                 }
-                sockets[usize::from(token)] = None;
-                *sockets_some_count -= 1;
-                *next_socket_index = usize::from(token);
-                #[cfg(test)]
+                HandleClientResult::Exit => is_exit = true,
+            }
+            buffer.clear();
+            if is_exit {
+                break;
+            }
+            if let Err(e) = socket.write_output_buffer() {
+                Self::log_error(&format!("write_output_buffer() error={e}"));
+                is_exit = true;
+                is_shutdown = true;
+                break;
+            }
+            if socket.is_waiting_google {
+                break;
+            }
+        }
+        if is_exit {
+            Self::close_socket(
+                poll,
+                sockets,
+                sockets_some_count,
+                next_socket_index,
+                shared,
+                token,
+                is_shutdown,
+            )?;
+            #[cfg(test)]
+            {
+                if take_count_for_test > 0
+                    && shared.connections.load(Ordering::SeqCst) == 0
+                    && shared.take_index_for_test.load(Ordering::SeqCst) >= take_count_for_test
                 {
-                    if take_count_for_test > 0
-                        && *sockets_some_count == 0
-                        && take_index_for_test >= take_count_for_test
-                    {
-                        return Ok(RunLoopTokenResult::Return);
-                    }
+                    return Ok(RunLoopTokenResult::Return);
                 }
             }
+        } else {
+            shared.update_connection(socket.serial, socket.last_active, socket.is_waiting_google);
+            socket.update_interest(poll, token)?;
         }
         #[cfg(test)]
         {
@@ -494,25 +1723,31 @@ impl Yaskkserv2 {
         buffer: &mut Vec<u8>,
         dictionary_file: &mut DictionaryFile,
         is_shutdown: &mut bool,
+        is_would_block: &mut bool,
+        access_log_entry: &mut AccessLogEntry,
     ) -> HandleClientResult {
+        buffer.append(&mut socket.partial_request);
         match socket.buffer_stream.read_until_skk_server(buffer) {
             Ok(0) => HandleClientResult::Exit,
-            Ok(size) => {
-                let skip = Self::get_buffer_skip_count(buffer, size);
-                if size == skip {
-                    HandleClientResult::Exit
-                } else if size - skip > 0 {
-                    self.server.handle_client(
-                        &mut socket.buffer_stream,
-                        dictionary_file,
-                        &mut buffer[skip..],
-                    )
-                } else {
-                    HandleClientResult::Continue
-                }
+            Ok(_) => {
+                let size = buffer.len();
+                self.handle_client_buffer(
+                    &mut socket.output_buffer,
+                    dictionary_file,
+                    buffer,
+                    size,
+                    access_log_entry,
+                )
             }
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::WouldBlock {
+                    *is_would_block = true;
+                    if buffer.len() > PROTOCOL_MAXIMUM_LENGTH {
+                        Self::log_warn("request too long");
+                        *is_shutdown = true;
+                        return HandleClientResult::Exit;
+                    }
+                    socket.partial_request.append(buffer);
                     HandleClientResult::Continue
                 } else {
                     match socket.buffer_stream.get_ref().peer_addr() {
@@ -536,6 +1771,51 @@ impl Yaskkserv2 {
         }
     }
 
+    /// `read_until_skk_server()` で読んだ `size` byte の request を処理する
+    ///
+    /// `HandleClientResult::Google` 以外は、処理した時点で `access_log_entry` を access log に
+    /// 書き込む。 `Google` の場合は `Server::handle_client_protocol_1_google()` で書き込む。
+    fn handle_client_buffer<S: TcpStreamSkk>(
+        &self,
+        stream: &mut S,
+        dictionary_file: &mut DictionaryFile,
+        buffer: &mut [u8],
+        size: usize,
+        access_log_entry: &mut AccessLogEntry,
+    ) -> HandleClientResult {
+        let skip = Self::get_buffer_skip_count(buffer, size);
+        if size == skip {
+            HandleClientResult::Exit
+        } else if size - skip > 0 {
+            self.server
+                .dictionary
+                .statistics
+                .count_request(buffer[skip]);
+            Self::log_debug(|| {
+                format!(
+                    "request protocol={} length={}",
+                    char::from(buffer[skip]),
+                    size - skip
+                )
+            });
+            if self.server.access_log.is_enabled() {
+                access_log_entry.set_request(&buffer[skip..]);
+            }
+            let result = self.server.handle_client(
+                stream,
+                dictionary_file,
+                &mut buffer[skip..],
+                access_log_entry,
+            );
+            if matches!(result, HandleClientResult::Continue) {
+                self.server.access_log.write(access_log_entry);
+            }
+            result
+        } else {
+            HandleClientResult::Continue
+        }
+    }
+
     const fn get_buffer_skip_count(buffer: &[u8], size: usize) -> usize {
         const SKIP_ZERO_INDEX: usize = 0;
         const SKIP_CR_OR_LF_INDEX: usize = 1;
@@ -567,57 +1847,162 @@ impl Yaskkserv2 {
         }
     }
 
-    #[cfg(test)]
     fn log_error(message: &str) {
-        println!("Error: {message}");
+        Logger::log(LogLevel::Error, message);
     }
 
-    #[cfg(all(not(test), unix))]
-    fn log_error(message: &str) {
-        match syslog::unix(Self::get_log_formatter()) {
-            Err(e) => println!("impossible to connect to syslog: {e:?}"),
-            Ok(mut writer) => {
-                writer.err(message).expect("could not write error message");
-            }
-        }
+    fn log_warn(message: &str) {
+        Logger::log(LogLevel::Warn, message);
     }
 
-    #[cfg(all(not(test), not(unix)))]
-    fn log_error(message: &str) {
-        error!("{}", message);
-    }
-
-    #[cfg(test)]
     fn log_info(message: &str) {
-        println!("Info: {message}");
+        Logger::log(LogLevel::Info, message);
     }
 
-    #[cfg(all(not(test), unix))]
-    fn log_info(message: &str) {
-        match syslog::unix(Self::get_log_formatter()) {
-            Err(e) => println!("impossible to connect to syslog: {e:?}"),
-            Ok(mut writer) => {
-                writer.info(message).expect("could not send message");
-            }
+    /// `message()` は debug log を出力する場合のみ呼ばれる
+    ///
+    /// request ごとに呼ばれるものがあるため、 format のコストを避ける。
+    fn log_debug(message: impl FnOnce() -> String) {
+        if Logger::is_enabled(LogLevel::Debug) {
+            Logger::log(LogLevel::Debug, &message());
         }
     }
+}
 
-    #[cfg(all(not(test), not(unix)))]
-    fn log_info(message: &str) {
-        info!("{}", message);
-    }
+/// log の level と出力先
+///
+/// `Yaskkserv2::setup()` で config から設定するまでは、 level は `LogLevel::Info` で syslog へ
+/// 出力する。
+pub(in crate::skk) struct Logger {
+    level: LogLevel,
+    writer: LogWriter,
+}
+
+enum LogWriter {
+    /// message ごとに接続せず、接続したものを使い続ける
+    ///
+    /// 接続や書き込みに失敗した場合は `None` にして、次の message で接続し直す。
+    #[cfg(all(not(test), unix))]
+    Syslog(Option<syslog::Logger<syslog::LoggerBackend, String, Formatter3164>>),
+    /// test では stdout に、 unix 以外では `log` crate に出力する
+    #[cfg(any(test, not(unix)))]
+    Syslog,
+    Stderr,
+    File(File),
 }
 
 struct Server {
     config: Config,
     dictionary: DictionaryReader,
+    access_log: AccessLog,
+}
+
+/// JSON Lines 形式の access log
+///
+/// `full_path` が空の場合は書き込まない。 size が `max_size` を越える場合は `full_path.1` から
+/// `full_path.{rotate_files}` へ rotate する。
+struct AccessLog {
+    full_path: String,
+    max_size: u64,
+    rotate_files: usize,
+    is_midashi_utf8: bool,
+    /// open している file と、その size
+    file: Mutex<Option<(File, u64)>>,
+}
+
+/// access log の 1 行分の情報
+///
+/// `request` は protocol の文字からはじまる client の request で、 access log が有効な場合のみ
+/// 設定する。 `sources` と `candidates` は `Server` が request を処理しながら設定する。
+struct AccessLogEntry {
+    peer: String,
+    request: Vec<u8>,
+    timestamp: SystemTime,
+    started: Instant,
+    sources: AccessLogSources,
+    candidates: usize,
+}
+
+/// candidates を返したもの
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Copy, Default)]
+pub(in crate::skk) struct AccessLogSources {
+    dictionary: bool,
+    google_cache: bool,
+    google_japanese_input: bool,
+    google_suggest: bool,
+    /// `GoogleFilter` で Google に送らなかった場合は、 access log にも midashi を書かない
+    google_blocked: bool,
 }
 
 pub(in crate::skk) struct DictionaryReader {
-    config: Config,
-    google_japanese_input_protocol: String,
-    google_suggest_protocol: String,
-    on_memory: OnMemory,
+    /// SIGHUP で一部の設定を実行中に差し替えるため `RwLock` で保持する
+    config: RwLock<Arc<Config>>,
+    /// URL encode した midashi を末尾に付けて request する URL
+    google_japanese_input_url: String,
+    google_suggest_url: String,
+    /// keep-alive の connection を使い回すため、全 thread で 1 つの client を共有する
+    ///
+    /// 最初の request で作成し、 `update_config()` で破棄する。 client は内部で thread を
+    /// 起動するので、 daemonize や sandbox の前には作成しないこと。
+    google_client: Mutex<Option<reqwest::blocking::Client>>,
+    google_limiter: GoogleLimiter,
+    google_filter: GoogleFilter,
+    loaded_dictionary: RwLock<LoadedDictionary>,
+    /// dictionary と Google Japanese Input の呼び出しも数えるため、ここで保持する
+    statistics: Statistics,
+}
+
+/// Google Japanese Input と Google Suggest の request の circuit breaker と request 数の制限
+///
+/// 全 thread で共有するので、状態は `Mutex` で保持する。
+pub(in crate::skk) struct GoogleLimiter {
+    state: Mutex<GoogleLimiterState>,
+}
+
+struct GoogleLimiterState {
+    /// 0 の場合は circuit breaker を使わない
+    circuit_breaker_failures: u32,
+    circuit_breaker_cooldown: Duration,
+    /// 0 の場合は制限しない
+    requests_per_minute: u32,
+    consecutive_failures: u32,
+    /// circuit breaker が open の場合は、 open にした (または cooldown 後に試した) 時刻
+    opened: Option<Instant>,
+    /// 1 request あたり `GOOGLE_LIMITER_TOKEN` で、 1 ms ごとに `requests_per_minute` 増える
+    tokens: u64,
+    refilled: Instant,
+}
+
+/// Google Japanese Input と Google Suggest に送ってよい midashi の条件
+///
+/// 条件に合わない midashi は cache も含めて Google の処理を行わない。 SIGHUP で差し替える
+/// ため `RwLock` で保持する。
+pub(in crate::skk) struct GoogleFilter {
+    rules: RwLock<GoogleFilterRules>,
+}
+
+struct GoogleFilterRules {
+    /// `None` の場合は全て許可する
+    allow: Option<Regex>,
+    /// `None` の場合は何も拒否しない
+    deny: Option<Regex>,
+    /// regex が不正な場合は、意図しない midashi を送らないよう全て拒否する
+    is_all_denied: bool,
+    /// 文字数。 0 の場合は制限しない
+    minimum_length: usize,
+    maximum_length: usize,
+    is_okuri_ari_denied: bool,
+    is_digits_denied: bool,
+}
+
+/// 使用中の dictionary
+///
+/// reload で丸ごと入れ替える。 `generation` は reload ごとに増え、 thread ごとに open している
+/// `DictionaryFile` を開き直す必要があるかの判定に使う。
+struct LoadedDictionary {
+    on_memory: Arc<OnMemory>,
+    generation: u64,
 }
 
 type GoogleCacheBTreeMap = BTreeMap<Vec<u8>, Vec<Vec<u8>>>;
@@ -635,4 +2020,185 @@ impl GoogleCacheObject {
 }
 
 pub(in crate::skk) struct GoogleCache;
-struct Request;
+pub(in crate::skk) struct Request;
+
+struct GoogleWorkerJob {
+    loop_index: usize,
+    token: Token,
+    serial: u64,
+    buffer: Vec<u8>,
+    access_log_entry: AccessLogEntry,
+}
+
+/// poll loop の thread 間で共有するもの
+///
+/// `connections` は全ての poll loop の connection 数で、 `max_connections` の判定に使う。
+/// `is_shutdown` は SIGTERM などによる終了の開始を表す。 poll loop は新しい connection を
+/// 受け付けなくなり、処理中の response を送信し終えた connection から閉じて終了する。
+///
+/// `is_draining` は upgrade による終了の開始を表す。 poll loop は新しい connection を受け付け
+/// なくなり、既存の connection の client が切断するまで処理を続けてから終了する。
+/// `is_upgrading` は upgrade を同時に実行しないためのもので、成功した場合は `true` のままと
+/// なる。
+///
+/// `next_socket_serial` は全ての poll loop で一意な `MioSocket` の `serial` を作るためのもの。
+/// `connection_list` は control socket の `connections` command で使う。 `service_wakers` は
+/// control socket と metrics の thread を終了させるためのもの。
+///
+/// `evictions` は poll loop 毎の、他の poll loop から閉じるよう依頼された connection の
+/// token と serial で、依頼した poll loop は `wakers` で依頼先の poll loop を起こす。
+pub(in crate::skk) struct RunLoopShared {
+    wakers: Vec<Waker>,
+    service_wakers: Vec<Waker>,
+    connections: AtomicUsize,
+    next_socket_serial: AtomicU64,
+    connection_list: Mutex<BTreeMap<u64, ConnectionInformation>>,
+    evictions: Vec<Mutex<Vec<(Token, u64)>>>,
+    is_exit: AtomicBool,
+    is_shutdown: AtomicBool,
+    is_draining: AtomicBool,
+    #[cfg(unix)]
+    is_upgrading: AtomicBool,
+    #[cfg(unix)]
+    upgrade_fds: UpgradeFds,
+    #[cfg(test)]
+    take_index_for_test: AtomicUsize,
+}
+
+impl RunLoopShared {
+    /// 全ての poll loop を終了させる
+    fn exit(&self) {
+        self.is_exit.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    /// 全ての poll loop を、処理中の response を送信し終えてから終了させる
+    fn shutdown(&self) {
+        self.is_shutdown.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    /// 全ての poll loop を、既存の connection の client が切断してから終了させる
+    #[cfg(unix)]
+    fn drain(&self) {
+        self.is_draining.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    /// `connection_list` の `serial` の connection の状態を `MioSocket` に合わせる
+    fn update_connection(&self, serial: u64, last_active: Instant, is_waiting_google: bool) {
+        if let Some(information) = self.connection_list.lock().unwrap().get_mut(&serial) {
+            information.last_active = last_active;
+            information.is_waiting_google = is_waiting_google;
+        }
+    }
+
+    /// control socket と metrics の thread を終了させるかどうかを返す
+    fn is_stopping(&self) -> bool {
+        self.is_exit.load(Ordering::SeqCst)
+            || self.is_shutdown.load(Ordering::SeqCst)
+            || self.is_draining.load(Ordering::SeqCst)
+    }
+
+    fn wake_all(&self) {
+        for waker in self.wakers.iter().chain(&self.service_wakers) {
+            if let Err(e) = waker.wake() {
+                Yaskkserv2::log_error(&format!("wake failed {e}"));
+            }
+        }
+    }
+}
+
+/// upgrade で新しい process に渡す listening socket の fd
+///
+/// `listeners` は `tcp` などの種類と fd の組。
+#[cfg(unix)]
+struct UpgradeFds {
+    listeners: Vec<(&'static str, std::os::unix::io::RawFd)>,
+    control: Option<std::os::unix::io::RawFd>,
+    metrics: Option<std::os::unix::io::RawFd>,
+}
+
+/// upgrade 元の process から継承した、 listening socket 以外のもの
+///
+/// `ready` は poll loop を開始できることを upgrade 元の process に通知する pipe。
+#[cfg(unix)]
+pub(in crate::skk) struct UpgradeHandover {
+    control_listener: Option<Listener>,
+    metrics_listener: Option<std::net::TcpListener>,
+    ready: File,
+}
+
+/// `connections` command で表示する connection の情報
+///
+/// `loop_index` 以降は、 max connections に達した場合に全ての poll loop の中から閉じる
+/// connection を選ぶためのもの。 `last_active` と `is_waiting_google` は `MioSocket` の写しで、
+/// `is_evicting` は閉じるよう依頼済みであることを表す。
+struct ConnectionInformation {
+    address: String,
+    connected: Instant,
+    loop_index: usize,
+    token: Token,
+    last_active: Instant,
+    is_waiting_google: bool,
+    is_evicting: bool,
+}
+
+/// 起動してからの統計
+///
+/// SIGUSR1 と control socket の `stats` で出力し、 `/metrics` で Prometheus に公開する。
+/// `protocol_requests` は protocol `0` から `4` と、それ以外の request 数。
+/// `rejected_connections_access_control` は allow-from と deny-from (peer の address を取得
+/// できない場合を含む) で、 `rejected_connections_max_connections` は max-connections で切断
+/// した connection 数。
+/// `google_requests` は Google Japanese Input を呼ぶために worker に渡した request 数で、
+/// cache に hit したものも含む。 `google_blocked` は `GoogleFilter` で Google に送らなかった数。
+struct Statistics {
+    started: Instant,
+    accepted_connections: AtomicU64,
+    rejected_connections_access_control: AtomicU64,
+    rejected_connections_max_connections: AtomicU64,
+    protocol_requests: [AtomicU64; STATISTICS_PROTOCOLS],
+    dictionary_hits: AtomicU64,
+    dictionary_misses: AtomicU64,
+    google_requests: AtomicU64,
+    google_blocked: AtomicU64,
+    google_japanese_input: GoogleStatistics,
+    google_suggest: GoogleStatistics,
+    google_cache_hits: AtomicU64,
+    google_cache_misses: AtomicU64,
+}
+
+/// Google Japanese Input と Google Suggest の API ごとの統計
+///
+/// `errors` に timeout は含まない。
+struct GoogleStatistics {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    /// circuit breaker と request 数の制限で request しなかった数。 `calls` には含まない
+    skipped: AtomicU64,
+    latency: Histogram,
+}
+
+/// `METRICS_LATENCY_BUCKETS` 秒ごとの Prometheus の histogram
+///
+/// `buckets` はそれぞれの上限以下の数で、累積はせず出力時に合計する。
+struct Histogram {
+    buckets: [AtomicU64; METRICS_LATENCY_BUCKETS.len()],
+    sum_microseconds: AtomicU64,
+    count: AtomicU64,
+}
+
+struct GoogleWorkerResult {
+    token: Token,
+    serial: u64,
+    response: Vec<u8>,
+}
+
+struct GoogleWorker<'a> {
+    server: &'a Server,
+    job_receiver: &'a Mutex<mpsc::Receiver<GoogleWorkerJob>>,
+    result_senders: Vec<mpsc::Sender<GoogleWorkerResult>>,
+    wakers: &'a [Waker],
+}
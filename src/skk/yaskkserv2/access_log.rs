@@ -0,0 +1,197 @@
+use crate::skk::yaskkserv2::{
+    encoding_simple, AccessLog, AccessLogEntry, AccessLogSources, Config, Instant, Mutex, SkkError,
+    SystemTime, Write, Yaskkserv2,
+};
+
+impl AccessLog {
+    pub(in crate::skk) fn new(config: &Config) -> Self {
+        Self {
+            full_path: config.access_log_full_path.clone(),
+            max_size: config.access_log_max_size,
+            rotate_files: config.access_log_rotate_files,
+            is_midashi_utf8: config.is_midashi_utf8,
+            file: Mutex::new(None),
+        }
+    }
+
+    pub(in crate::skk) const fn is_enabled(&self) -> bool {
+        !self.full_path.is_empty()
+    }
+
+    /// `entry` を 1 行の JSON として書き込む
+    ///
+    /// 書き込みに失敗しても request の処理は継続するため、 error は log に出力するのみ。
+    pub(in crate::skk) fn write(&self, entry: &AccessLogEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut line = self.get_json(entry).dump();
+        line.push('\n');
+        if let Err(e) = self.write_line(line.as_bytes()) {
+            Yaskkserv2::log_error(&format!("access log write failed error={e}"));
+        }
+    }
+
+    fn get_json(&self, entry: &AccessLogEntry) -> json::JsonValue {
+        let mut object = json::JsonValue::new_object();
+        object["timestamp"] = Self::format_timestamp(entry.timestamp).into();
+        object["peer"] = entry.peer.as_str().into();
+        object["protocol"] = entry
+            .request
+            .first()
+            .map_or_else(String::new, |protocol| char::from(*protocol).to_string())
+            .into();
+        if entry.sources.google_blocked {
+            object["google_blocked"] = true.into();
+        } else if let Some(midashi) = self.get_midashi(&entry.request) {
+            object["midashi"] = midashi.into();
+        }
+        let sources = [
+            ("dictionary", entry.sources.dictionary),
+            ("cache", entry.sources.google_cache),
+            ("google", entry.sources.google_japanese_input),
+            ("suggest", entry.sources.google_suggest),
+        ];
+        object["sources"] = sources
+            .iter()
+            .filter(|(_, is_answered)| *is_answered)
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .into();
+        object["candidates"] = entry.candidates.into();
+        object["latency_us"] = u64::try_from(entry.started.elapsed().as_micros())
+            .unwrap_or(u64::MAX)
+            .into();
+        object
+    }
+
+    /// protocol 1 と 4 の midashi を UTF-8 で返す
+    fn get_midashi(&self, request: &[u8]) -> Option<String> {
+        if !matches!(request.first(), Some(b'1' | b'4')) {
+            return None;
+        }
+        let midashi = request[1..]
+            .split(|c| *c == b' ')
+            .next()
+            .unwrap_or_default();
+        if self.is_midashi_utf8 {
+            return Some(String::from_utf8_lossy(midashi).into_owned());
+        }
+        Some(encoding_simple::Euc::decode(midashi).map_or_else(
+            |_| String::from_utf8_lossy(midashi).into_owned(),
+            |utf8_midashi| String::from_utf8_lossy(&utf8_midashi).into_owned(),
+        ))
+    }
+
+    fn write_line(&self, line: &[u8]) -> Result<(), SkkError> {
+        let mut file = self.file.lock().unwrap();
+        if let Some((_, size)) = file.as_ref() {
+            if *size > 0 && *size + line.len() as u64 > self.max_size {
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        if file.is_none() {
+            let opened = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.full_path)?;
+            let size = opened.metadata()?.len();
+            *file = Some((opened, size));
+        }
+        if let Some((opened, size)) = file.as_mut() {
+            opened.write_all(line)?;
+            *size += line.len() as u64;
+        }
+        drop(file);
+        Ok(())
+    }
+
+    /// `full_path` を `full_path.1` へ、 `full_path.1` を `full_path.2` へ、のように rename する
+    ///
+    /// `rotate_files` が 0 の場合は `full_path` を削除する。
+    fn rotate(&self) -> Result<(), SkkError> {
+        if self.rotate_files == 0 {
+            std::fs::remove_file(&self.full_path)?;
+            return Ok(());
+        }
+        for index in (1..self.rotate_files).rev() {
+            let from = format!("{}.{index}", self.full_path);
+            if std::path::Path::new(&from).exists() {
+                std::fs::rename(&from, format!("{}.{}", self.full_path, index + 1))?;
+            }
+        }
+        std::fs::rename(&self.full_path, format!("{}.1", self.full_path))?;
+        Ok(())
+    }
+
+    /// `2024-01-02T03:04:05.678Z` の形式で返す
+    pub(in crate::skk) fn format_timestamp(timestamp: SystemTime) -> String {
+        const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+        let duration = timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let seconds = duration.as_secs();
+        let (year, month, day) = Self::get_civil_from_days(seconds / SECONDS_PER_DAY);
+        let seconds_of_day = seconds % SECONDS_PER_DAY;
+        format!(
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+            duration.subsec_millis()
+        )
+    }
+
+    /// 1970-01-01 からの日数を (年, 月, 日) に変換する
+    ///
+    /// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days> を 1970 年以降に
+    /// 限定したもの。
+    const fn get_civil_from_days(days: u64) -> (u64, u64, u64) {
+        const DAYS_PER_ERA: u64 = 146_097;
+        let days = days + 719_468;
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+}
+
+impl AccessLogEntry {
+    pub(in crate::skk) fn new(peer: &str) -> Self {
+        Self {
+            peer: String::from(peer),
+            request: Vec::new(),
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+            sources: AccessLogSources::default(),
+            candidates: 0,
+        }
+    }
+
+    /// 処理を開始する `request` を設定し、 latency の計測を開始する
+    pub(in crate::skk) fn set_request(&mut self, request: &[u8]) {
+        self.request = request.to_vec();
+        self.timestamp = SystemTime::now();
+        self.started = Instant::now();
+    }
+
+    /// `b'1'` からはじまる `candidates` の数を設定する
+    pub(in crate::skk) fn set_candidates(&mut self, candidates: &[u8]) {
+        self.candidates = if Yaskkserv2::is_empty_candidates(candidates) {
+            0
+        } else {
+            candidates[1..].split(|c| *c == b'/').count() - 2
+        };
+    }
+}
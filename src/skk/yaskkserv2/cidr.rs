@@ -0,0 +1,52 @@
+use crate::skk::yaskkserv2::{Cidr, IpAddr};
+
+impl Cidr {
+    /// `ADDR/PREFIX-LENGTH` または `ADDR` を parse する
+    pub(in crate::skk) fn parse(cidr: &str) -> Option<Self> {
+        let (ip_address, prefix_length) = match cidr.split_once('/') {
+            Some((ip_address, prefix_length)) => (
+                ip_address.parse::<IpAddr>().ok()?,
+                Some(prefix_length.parse::<u8>().ok()?),
+            ),
+            None => (cidr.parse::<IpAddr>().ok()?, None),
+        };
+        let max_prefix_length = Self::get_max_prefix_length(ip_address);
+        let prefix_length = prefix_length.unwrap_or(max_prefix_length);
+        if prefix_length > max_prefix_length {
+            return None;
+        }
+        Some(Self {
+            ip_address,
+            prefix_length,
+        })
+    }
+
+    /// `ip_address` が範囲に含まれるかを返す
+    ///
+    /// `[::]` で listen した場合の IPv4 の client は `::ffff:a.b.c.d` となるので、 IPv4 として
+    /// 比較する。
+    pub(in crate::skk) fn contains(&self, ip_address: IpAddr) -> bool {
+        match (self.ip_address, ip_address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip_address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip_address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip_address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip_address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    const fn get_max_prefix_length(ip_address: IpAddr) -> u8 {
+        match ip_address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
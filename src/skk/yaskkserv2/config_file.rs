@@ -3,6 +3,7 @@ use rustc_hash::FxHashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
+use crate::skk::yaskkserv2::{Cidr, Logger};
 use crate::skk::{yaskkserv2, Config, GoogleTiming, SkkError};
 
 #[derive(Default)]
@@ -84,6 +85,22 @@ impl Yaskkserv2ConfigFile {
             };
         }
 
+        macro_rules! validate_and_set_config_string {
+            ($key: tt, $field: ident) => {
+                if candidates.contains_key($key) && self.config.$field == self.default_config.$field
+                {
+                    self.config.$field.clone_from(&candidates[$key]);
+                }
+            };
+            ($key: tt, $field: ident, $validator: ident) => {
+                if candidates.contains_key($key) && self.config.$field == self.default_config.$field
+                {
+                    yaskkserv2::command_line::Yaskkserv2CommandLine::$validator(&candidates[$key])?;
+                    self.config.$field.clone_from(&candidates[$key]);
+                }
+            };
+        }
+
         {
             let key = "dictionary";
             if candidates.contains_key(key) && self.config.dictionary_full_path.is_empty() {
@@ -104,17 +121,147 @@ impl Yaskkserv2ConfigFile {
             max_connections,
             max_connections_validator
         );
+        validate_and_set_config_integer!(
+            "worker-threads",
+            worker_threads,
+            worker_threads_validator
+        );
+        validate_and_set_config_integer!(
+            "idle-timeout-seconds",
+            idle_timeout_seconds,
+            idle_timeout_seconds_validator
+        );
         {
             let key = "listen-address";
             if candidates.contains_key(key)
-                && self.config.listen_address == self.default_config.listen_address
+                && self.config.listen_addresses == self.default_config.listen_addresses
+            {
+                let mut listen_addresses = Vec::new();
+                for listen_address in candidates[key].split(',').map(str::trim) {
+                    yaskkserv2::command_line::Yaskkserv2CommandLine::listen_address_validator(
+                        listen_address,
+                    )?;
+                    listen_addresses.push(String::from(listen_address));
+                }
+                self.config.listen_addresses = listen_addresses;
+            }
+        }
+        validate_and_set_config_string!("listen-unix-socket", listen_unix_socket);
+        validate_and_set_config_string!("control-socket", control_socket);
+        validate_and_set_config_string!(
+            "metrics-address",
+            metrics_address,
+            metrics_address_validator
+        );
+        {
+            let key = "listen-unix-socket-mode";
+            if candidates.contains_key(key)
+                && self.config.listen_unix_socket_mode
+                    == self.default_config.listen_unix_socket_mode
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::listen_unix_socket_mode_validator(
+                    &candidates[key],
+                )?;
+                self.config.listen_unix_socket_mode =
+                    yaskkserv2::command_line::Yaskkserv2CommandLine::parse_mode(
+                        &candidates[key],
+                        &mut parse_integer_result,
+                    );
+            }
+        }
+        macro_rules! validate_and_set_config_cidrs {
+            ($key: tt, $field: ident) => {
+                if candidates.contains_key($key) && self.config.$field == self.default_config.$field
+                {
+                    let mut cidrs = Vec::new();
+                    for cidr in candidates[$key].split(',').map(str::trim) {
+                        yaskkserv2::command_line::Yaskkserv2CommandLine::cidr_validator(cidr)?;
+                        cidrs.extend(Cidr::parse(cidr));
+                    }
+                    self.config.$field = cidrs;
+                }
+            };
+        }
+        validate_and_set_config_cidrs!("allow-from", allow_from);
+        validate_and_set_config_cidrs!("deny-from", deny_from);
+        {
+            let key = "tls-port";
+            if candidates.contains_key(key) && self.config.tls_port == self.default_config.tls_port
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::port_validator(&candidates[key])?;
+                self.config.tls_port.clone_from(&candidates[key]);
+            }
+        }
+        validate_and_set_config_string!("access-log", access_log_full_path);
+        validate_and_set_config_integer!(
+            "access-log-max-size",
+            access_log_max_size,
+            access_log_max_size_validator
+        );
+        validate_and_set_config_integer!(
+            "access-log-rotate-files",
+            access_log_rotate_files,
+            access_log_rotate_files_validator
+        );
+        {
+            let key = "log-target";
+            if candidates.contains_key(key)
+                && self.config.log_target == self.default_config.log_target
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::log_target_validator(
+                    &candidates[key],
+                )?;
+                if let Some(target) = Logger::parse_target(&candidates[key]) {
+                    self.config.log_target = target;
+                }
+            }
+        }
+        {
+            let key = "log-level";
+            if candidates.contains_key(key)
+                && self.config.log_level == self.default_config.log_level
             {
-                yaskkserv2::command_line::Yaskkserv2CommandLine::listen_address_validator(
+                yaskkserv2::command_line::Yaskkserv2CommandLine::log_level_validator(
                     &candidates[key],
                 )?;
-                self.config.listen_address.clone_from(&candidates[key]);
+                if let Some(level) = Logger::parse_level(&candidates[key]) {
+                    self.config.log_level = level;
+                }
             }
         }
+        validate_and_set_config_string!("pid-file", pid_file_full_path);
+        {
+            let key = "user";
+            if candidates.contains_key(key) && self.config.user == self.default_config.user {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::user_validator(&candidates[key])?;
+                self.config.user.clone_from(&candidates[key]);
+            }
+        }
+        {
+            let key = "group";
+            if candidates.contains_key(key) && self.config.group == self.default_config.group {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::group_validator(&candidates[key])?;
+                self.config.group.clone_from(&candidates[key]);
+            }
+        }
+        validate_and_set_config_string!("daemon-log-file", daemon_log_full_path);
+        validate_and_set_config_string!("working-directory", working_directory);
+        {
+            let key = "sandbox";
+            if candidates.contains_key(key)
+                && self.config.is_sandbox_enabled == self.default_config.is_sandbox_enabled
+            {
+                self.config.is_sandbox_enabled = Regex::new(r"^(?i)\s*enable\s*$")
+                    .unwrap()
+                    .is_match(&candidates[key]);
+            }
+        }
+        validate_and_set_config_string!("tls-certificate", tls_certificate_full_path);
+        validate_and_set_config_string!("tls-private-key", tls_private_key_full_path);
+        validate_and_set_config_string!(
+            "tls-client-ca-certificate",
+            tls_client_ca_certificate_full_path
+        );
         {
             let key = "hostname-and-ip-address-for-protocol-3";
             if candidates.contains_key(key)
@@ -156,6 +303,53 @@ impl Yaskkserv2ConfigFile {
             google_max_candidates_length,
             google_max_candidates_length_validator
         );
+        validate_and_set_config_integer!(
+            "google-circuit-breaker-failures",
+            google_circuit_breaker_failures,
+            google_circuit_breaker_failures_validator
+        );
+        validate_and_set_config_integer!(
+            "google-circuit-breaker-cooldown-seconds",
+            google_circuit_breaker_cooldown_seconds,
+            google_circuit_breaker_cooldown_seconds_validator
+        );
+        validate_and_set_config_integer!(
+            "google-requests-per-minute",
+            google_requests_per_minute,
+            google_requests_per_minute_validator
+        );
+        {
+            let key = "google-allow-regex";
+            if candidates.contains_key(key)
+                && self.config.google_allow_regex == self.default_config.google_allow_regex
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::google_regex_validator(
+                    &candidates[key],
+                )?;
+                self.config.google_allow_regex.clone_from(&candidates[key]);
+            }
+        }
+        {
+            let key = "google-deny-regex";
+            if candidates.contains_key(key)
+                && self.config.google_deny_regex == self.default_config.google_deny_regex
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::google_regex_validator(
+                    &candidates[key],
+                )?;
+                self.config.google_deny_regex.clone_from(&candidates[key]);
+            }
+        }
+        validate_and_set_config_integer!(
+            "google-minimum-midashi-length",
+            google_minimum_midashi_length,
+            google_midashi_length_validator
+        );
+        validate_and_set_config_integer!(
+            "google-maximum-midashi-length",
+            google_maximum_midashi_length,
+            google_midashi_length_validator
+        );
         validate_and_set_config_integer!(
             "max-server-completions",
             max_server_completions,
@@ -179,6 +373,43 @@ impl Yaskkserv2ConfigFile {
             }
         }
         validate_and_set_config_google_bool!("google-use-http", is_http_enabled);
+        {
+            let key = "google-japanese-input-url";
+            if candidates.contains_key(key)
+                && self.config.google_japanese_input_url
+                    == self.default_config.google_japanese_input_url
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::google_url_validator(
+                    &candidates[key],
+                )?;
+                self.config
+                    .google_japanese_input_url
+                    .clone_from(&candidates[key]);
+            }
+        }
+        {
+            let key = "google-suggest-url";
+            if candidates.contains_key(key)
+                && self.config.google_suggest_url == self.default_config.google_suggest_url
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::google_url_validator(
+                    &candidates[key],
+                )?;
+                self.config.google_suggest_url.clone_from(&candidates[key]);
+            }
+        }
+        {
+            let key = "google-proxy";
+            if candidates.contains_key(key)
+                && self.config.google_proxy == self.default_config.google_proxy
+            {
+                yaskkserv2::command_line::Yaskkserv2CommandLine::google_url_validator(
+                    &candidates[key],
+                )?;
+                self.config.google_proxy.clone_from(&candidates[key]);
+            }
+        }
+        validate_and_set_config_string!("google-ca-certificate", google_ca_certificate_full_path);
         validate_and_set_config_google_bool!("google-suggest", is_google_suggest_enabled);
         validate_and_set_config_google_bool!(
             "google-insert-hiragana-only-candidate",
@@ -192,6 +423,8 @@ impl Yaskkserv2ConfigFile {
             "google-insert-hankaku-katakana-only-candidate",
             google_insert_hankaku_katakana_only_candidate
         );
+        validate_and_set_config_google_bool!("google-deny-okuri-ari", google_deny_okuri_ari);
+        validate_and_set_config_google_bool!("google-deny-digits", google_deny_digits);
         Ok(())
     }
 }
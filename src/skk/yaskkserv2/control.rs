@@ -0,0 +1,202 @@
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+
+use crate::skk::yaskkserv2::{
+    BufRead, BufReader, Config, DeadlineStream, Events, GoogleCache, Interest, Listener,
+    MioListener, Ordering, Poll, RunLoopShared, Shutdown, SkkError, Token, Write, Yaskkserv2,
+    CONTROL_COMMAND_DEADLINE, CONTROL_TIMEOUT, UPGRADE_READY_TIMEOUT,
+};
+
+const CONTROL_LISTENER: Token = Token(0);
+
+/// control socket の response の最後の行
+///
+/// response は 0 行以上の結果と、 `ok` または `error MESSAGE` の行からなる。
+const CONTROL_RESPONSE_OK: &str = "ok";
+const CONTROL_RESPONSE_ERROR: &str = "error";
+
+impl Yaskkserv2 {
+    /// control socket で受け付けた command を処理する
+    ///
+    /// 1 行 1 command で、 client が切断するまで処理する。 `shared` の `exit()` か
+    /// `shutdown()` か `drain()` で終了する。 `upgrade` command の後は、 control socket は新しい
+    /// process が受け付ける。
+    pub(in crate::skk) fn run_control(
+        &self,
+        mut poll: Poll,
+        control_listener: &Listener,
+        shared: &RunLoopShared,
+    ) -> Result<(), SkkError> {
+        let MioListener::Unix(mut listener) = control_listener.try_clone_mio()? else {
+            return Err(SkkError::CommandLine);
+        };
+        poll.registry()
+            .register(&mut listener, CONTROL_LISTENER, Interest::READABLE)?;
+        let mut events = Events::with_capacity(1);
+        loop {
+            poll.poll(&mut events, None)?;
+            if shared.is_stopping() {
+                return Ok(());
+            }
+            if events.iter().all(|event| event.token() != CONTROL_LISTENER) {
+                continue;
+            }
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        // 1 つの client の処理中は他の client を待たせるが、 command を待つのは
+                        // CONTROL_COMMAND_DEADLINE まで
+                        if let Err(e) = self.handle_control_client(stream, shared) {
+                            Self::log_error(&format!("control client error={e}"));
+                        }
+                        if shared.is_stopping() {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(SkkError::Io(e)),
+                }
+            }
+        }
+    }
+
+    fn handle_control_client(
+        &self,
+        stream: mio::net::UnixStream,
+        shared: &RunLoopShared,
+    ) -> Result<(), SkkError> {
+        let stream = unsafe { UnixStream::from_raw_fd(stream.into_raw_fd()) };
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+        let mut buffer_stream =
+            BufReader::new(DeadlineStream::new(stream, CONTROL_COMMAND_DEADLINE));
+        let mut line = String::new();
+        while buffer_stream.read_line(&mut line)? > 0 {
+            let command = line.trim();
+            if !command.is_empty() {
+                let response = match self.run_control_command(command, shared) {
+                    Ok(mut lines) => {
+                        lines.push(String::from(CONTROL_RESPONSE_OK));
+                        lines
+                    }
+                    Err(message) => vec![format!("{CONTROL_RESPONSE_ERROR} {message}")],
+                };
+                let stream = buffer_stream.get_mut();
+                for response_line in response {
+                    writeln!(stream, "{response_line}")?;
+                }
+                stream.flush()?;
+            }
+            line.clear();
+            // upgrade などの処理にかかった時間は含めない
+            buffer_stream.get_mut().restart_deadline();
+        }
+        Ok(())
+    }
+
+    /// `command` を実行し、結果の行を返す
+    fn run_control_command(
+        &self,
+        command: &str,
+        shared: &RunLoopShared,
+    ) -> Result<Vec<String>, String> {
+        let (name, argument) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+        match (name, argument) {
+            ("stats", "") => Ok(self
+                .get_statistics(shared.connections.load(Ordering::SeqCst))
+                .iter()
+                .map(|(name, value)| format!("{name} {value}"))
+                .collect()),
+            ("reload-dictionary", "") => self
+                .reload_dictionary()
+                .map(|()| Vec::new())
+                .map_err(|e| e.to_string()),
+            ("reload-config", "") => {
+                let result = self.reload_config().map(|()| Vec::new());
+                // idle timeout の変更を poll loop に反映させる
+                shared.wake_all();
+                result.map_err(|e| e.to_string())
+            }
+            ("flush-google-cache", "") => {
+                let config = self.server.dictionary.get_config();
+                if !config.is_google_cache_enabled {
+                    return Err(String::from("google cache disabled"));
+                }
+                GoogleCache::flush(&config.google_cache_full_path)
+                    .map(|()| Vec::new())
+                    .map_err(|e| e.to_string())
+            }
+            ("drop-cache-entry", midashi) if !midashi.is_empty() => {
+                let config = self.server.dictionary.get_config();
+                if !config.is_google_cache_enabled {
+                    return Err(String::from("google cache disabled"));
+                }
+                match GoogleCache::remove(midashi.as_bytes(), &config.google_cache_full_path) {
+                    Ok(true) => Ok(Vec::new()),
+                    Ok(false) => Err(format!("{midashi} not found")),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            ("connections", "") => Ok(shared
+                .connection_list
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(serial, information)| {
+                    format!(
+                        "{serial} {} {}",
+                        information.address,
+                        information.connected.elapsed().as_secs()
+                    )
+                })
+                .collect()),
+            ("upgrade", "") => self
+                .upgrade(shared)
+                .map(|()| Vec::new())
+                .map_err(|e| e.to_string()),
+            ("shutdown", "") => {
+                Self::log_info("shutdown requested by control socket");
+                shared.shutdown();
+                Ok(Vec::new())
+            }
+            _ => Err(format!("unknown command {command}")),
+        }
+    }
+
+    /// `--control` で指定された `command` を control socket に送信し、結果を表示する
+    pub(in crate::skk) fn run_control_client(
+        config: &Config,
+        command: &str,
+    ) -> Result<(), SkkError> {
+        if config.control_socket.is_empty() {
+            return Err(SkkError::Control(String::from(
+                "control-socket is not specified",
+            )));
+        }
+        let stream = UnixStream::connect(&config.control_socket)?;
+        // upgrade は新しい process が dictionary を読み込むまで response を返さない
+        stream.set_read_timeout(Some(if command == "upgrade" {
+            UPGRADE_READY_TIMEOUT + CONTROL_TIMEOUT
+        } else {
+            CONTROL_TIMEOUT
+        }))?;
+        let mut buffer_stream = BufReader::new(stream);
+        writeln!(buffer_stream.get_mut(), "{command}")?;
+        buffer_stream.get_mut().shutdown(Shutdown::Write)?;
+        for line in buffer_stream.lines() {
+            let line = line?;
+            if line == CONTROL_RESPONSE_OK {
+                return Ok(());
+            }
+            if let Some(message) = line.strip_prefix(CONTROL_RESPONSE_ERROR) {
+                return Err(SkkError::Control(String::from(message.trim_start())));
+            }
+            println!("{line}");
+        }
+        Err(SkkError::Io(std::io::Error::from(
+            std::io::ErrorKind::UnexpectedEof,
+        )))
+    }
+}
@@ -2,9 +2,12 @@
 use crate::const_panic;
 #[allow(unused_imports)]
 use crate::skk::yaskkserv2::{
-    encoding_simple, Candidates, Config, Dictionary, DictionaryBlockInformation, DictionaryFile,
-    DictionaryMidashiKey, DictionaryReader, Encoding, GoogleCache, GoogleTiming, OnMemory, Request,
-    SkkError, Yaskkserv2, MIDASHI_VEC_CAPACITY, PROTOCOL_MINIMUM_LENGTH, RESULT_VEC_CAPACITY,
+    encoding_simple, AccessLogSources, Arc, Candidates, Config, Dictionary,
+    DictionaryBlockInformation, DictionaryFile, DictionaryMidashiKey, DictionaryReader, Encoding,
+    GoogleCache, GoogleFilter, GoogleLimiter, GoogleTiming, LoadedDictionary, Mutex, OnMemory,
+    Ordering, Request, RwLock, SkkError, Statistics, Yaskkserv2, GOOGLE_JAPANESE_INPUT_URL,
+    GOOGLE_SUGGEST_URL, MIDASHI_VEC_CAPACITY, PROTOCOL_MINIMUM_LENGTH, RESULT_VEC_CAPACITY,
+    SHA1_READ_BUFFER_LENGTH,
 };
 
 const BINARY_SEARCH_THRESHOLD: usize = 30;
@@ -12,20 +15,136 @@ const BINARY_SEARCH_THRESHOLD: usize = 30;
 impl DictionaryReader {
     pub(in crate::skk) fn new() -> Self {
         Self {
-            config: Config::new(),
-            on_memory: OnMemory::new(),
-            google_japanese_input_protocol: String::from("https"),
-            google_suggest_protocol: String::from("https"),
+            config: RwLock::new(Arc::new(Config::new())),
+            loaded_dictionary: RwLock::new(LoadedDictionary {
+                on_memory: Arc::new(OnMemory::new()),
+                generation: 0,
+            }),
+            google_japanese_input_url: format!("https{GOOGLE_JAPANESE_INPUT_URL}"),
+            google_suggest_url: format!("https{GOOGLE_SUGGEST_URL}"),
+            google_client: Mutex::new(None),
+            google_limiter: GoogleLimiter::new(&Config::new()),
+            google_filter: GoogleFilter::new(&Config::new()),
+            statistics: Statistics::new(),
         }
     }
 
+    /// config で URL が指定されていない Google Japanese Input と Google Suggest は、
+    /// `is_http_enabled` に応じて http または https の Google の URL を使う
     pub(in crate::skk) fn setup(&mut self, config: Config, on_memory: OnMemory) {
-        self.config = config;
-        self.on_memory = on_memory;
-        if self.config.is_http_enabled {
-            self.google_japanese_input_protocol = String::from("http");
-            self.google_suggest_protocol = String::from("http");
+        let protocol = if config.is_http_enabled {
+            "http"
+        } else {
+            "https"
+        };
+        self.google_japanese_input_url = if config.google_japanese_input_url.is_empty() {
+            format!("{protocol}{GOOGLE_JAPANESE_INPUT_URL}")
+        } else {
+            config.google_japanese_input_url.clone()
+        };
+        self.google_suggest_url = if config.google_suggest_url.is_empty() {
+            format!("{protocol}{GOOGLE_SUGGEST_URL}")
+        } else {
+            config.google_suggest_url.clone()
+        };
+        self.google_limiter = GoogleLimiter::new(&config);
+        self.google_filter = GoogleFilter::new(&config);
+        self.config = RwLock::new(Arc::new(config));
+        self.loaded_dictionary = RwLock::new(LoadedDictionary {
+            on_memory: Arc::new(on_memory),
+            generation: 0,
+        });
+    }
+
+    /// 使用中の config を返す
+    ///
+    /// `update_config()` で入れ替わるので、 1 つの request の処理中は同じものを使うこと。
+    pub(in crate::skk) fn get_config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// `config` のうち、実行中に変更できる設定を反映する
+    ///
+    /// Google Japanese Input の worker thread は起動時にしか作らないので、 `google_timing` の
+    /// `GoogleTiming::Disable` とそれ以外の切り替えは反映せず `false` を返す。
+    pub(in crate::skk) fn update_config(&self, config: &Config) -> bool {
+        let mut new_config = (*self.get_config()).clone();
+        let is_google_timing_updatable = (new_config.google_timing == GoogleTiming::Disable)
+            == (config.google_timing == GoogleTiming::Disable);
+        if is_google_timing_updatable {
+            new_config.google_timing = config.google_timing.clone();
         }
+        new_config.google_timeout_milliseconds = config.google_timeout_milliseconds;
+        new_config.google_max_candidates_length = config.google_max_candidates_length;
+        new_config.google_cache_entries = config.google_cache_entries;
+        new_config.google_cache_expire_seconds = config.google_cache_expire_seconds;
+        new_config.is_google_suggest_enabled = config.is_google_suggest_enabled;
+        new_config.google_insert_hiragana_only_candidate =
+            config.google_insert_hiragana_only_candidate;
+        new_config.google_insert_katakana_only_candidate =
+            config.google_insert_katakana_only_candidate;
+        new_config.google_insert_hankaku_katakana_only_candidate =
+            config.google_insert_hankaku_katakana_only_candidate;
+        new_config.google_circuit_breaker_failures = config.google_circuit_breaker_failures;
+        new_config.google_circuit_breaker_cooldown_seconds =
+            config.google_circuit_breaker_cooldown_seconds;
+        new_config.google_requests_per_minute = config.google_requests_per_minute;
+        new_config
+            .google_allow_regex
+            .clone_from(&config.google_allow_regex);
+        new_config
+            .google_deny_regex
+            .clone_from(&config.google_deny_regex);
+        new_config.google_minimum_midashi_length = config.google_minimum_midashi_length;
+        new_config.google_maximum_midashi_length = config.google_maximum_midashi_length;
+        new_config.google_deny_okuri_ari = config.google_deny_okuri_ari;
+        new_config.google_deny_digits = config.google_deny_digits;
+        new_config.max_server_completions = config.max_server_completions;
+        new_config.idle_timeout_seconds = config.idle_timeout_seconds;
+        *self.config.write().unwrap() = Arc::new(new_config);
+        self.google_limiter.update_config(config);
+        self.google_filter.update_config(config);
+        // timeout を反映するため、次の request で作り直す
+        *self.google_client.lock().unwrap() = None;
+        is_google_timing_updatable
+    }
+
+    /// `dictionary_full_path` の dictionary を読み直し、使用中の dictionary と入れ替える
+    ///
+    /// 読み込みと SHA1 の検証は lock の外で行うので、その間も使用中の dictionary で request を
+    /// 処理できる。検証に失敗した場合は `Err` を返し、使用中の dictionary はそのまま使い続ける。
+    /// 各 thread の `DictionaryFile` は、次の request で `get_on_memory()` が開き直す。
+    pub(in crate::skk) fn reload(&self) -> Result<(), SkkError> {
+        let on_memory = Arc::new(Dictionary::setup(
+            SHA1_READ_BUFFER_LENGTH,
+            &self.get_config().dictionary_full_path,
+        )?);
+        {
+            let mut loaded_dictionary = self.loaded_dictionary.write().unwrap();
+            loaded_dictionary.on_memory = on_memory;
+            loaded_dictionary.generation += 1;
+        }
+        Ok(())
+    }
+
+    /// 使用中の `OnMemory` を返す
+    ///
+    /// `dictionary_file` が reload 前の dictionary を open したものであれば開き直す。
+    fn get_on_memory(
+        &self,
+        dictionary_file: &mut DictionaryFile,
+    ) -> Result<Arc<OnMemory>, SkkError> {
+        let (on_memory, generation) = {
+            let loaded_dictionary = self.loaded_dictionary.read().unwrap();
+            (
+                Arc::clone(&loaded_dictionary.on_memory),
+                loaded_dictionary.generation,
+            )
+        };
+        if dictionary_file.generation != generation {
+            dictionary_file.reopen(&self.get_config().dictionary_full_path, generation)?;
+        }
+        Ok(on_memory)
     }
 
     /// `midashi_buffer` にある midashi から対応する candidates を返す
@@ -35,37 +154,128 @@ impl DictionaryReader {
     /// 戻り値は常に先頭に `b'1'` が付加されるため candidates が見付からなかった場合でも
     /// `result.len() == 0 / result.is_empty()` とはならないことに注意。
     /// 見付からなかった場合の判定には `Yaskkserv2::is_empty_candidates(&result)` を使うこと。
+    #[cfg(test)]
     pub(in crate::skk) fn read_candidates(
         &self,
         dictionary_file: &mut DictionaryFile,
         midashi_buffer: &[u8],
     ) -> Result<Vec<u8>, SkkError> {
+        self.read_candidates_with_sources(
+            dictionary_file,
+            midashi_buffer,
+            &mut AccessLogSources::default(),
+        )
+    }
+
+    /// `read_candidates()` と同じ candidates を返し、 candidates を返したものを `sources` に
+    /// 設定する
+    pub(in crate::skk) fn read_candidates_with_sources(
+        &self,
+        dictionary_file: &mut DictionaryFile,
+        midashi_buffer: &[u8],
+        sources: &mut AccessLogSources,
+    ) -> Result<Vec<u8>, SkkError> {
+        let config = self.get_config();
+        let on_memory = self.get_on_memory(dictionary_file)?;
         let mut result = Vec::with_capacity(RESULT_VEC_CAPACITY);
         result.push(b'1');
         let midashi = Self::get_midashi(midashi_buffer);
         let dictionary_midashi_key = Dictionary::get_dictionary_midashi_key(&midashi_buffer[1..])?;
-        if self.config.google_timing == GoogleTiming::First {
+        if config.google_timing == GoogleTiming::First {
             // Google API など、外部要因エラーは無視して継続させることに注意
-            let _ignore_error_and_continue = self.read_google_candidates(midashi, &mut result);
+            let _ignore_error_and_continue =
+                self.read_google_candidates(&config, &on_memory, midashi, &mut result, sources);
+        }
+        let result_length = result.len();
+        Self::read_dictionary_candidates(
+            &on_memory,
+            dictionary_file,
+            midashi,
+            dictionary_midashi_key,
+            &mut result,
+        )?;
+        // `GoogleTiming::NotFound` では `read_candidates_without_google()` で数えている
+        sources.dictionary = result.len() > result_length;
+        if config.google_timing != GoogleTiming::NotFound {
+            self.statistics.count_dictionary(sources.dictionary);
+        }
+        if config.google_timing == GoogleTiming::Last
+            || (config.google_timing == GoogleTiming::NotFound
+                && Yaskkserv2::is_empty_candidates(&result))
+        {
+            let _ignore_error_and_continue =
+                self.read_google_candidates(&config, &on_memory, midashi, &mut result, sources);
+        }
+        Ok(result)
+    }
+
+    /// Google Japanese Input を呼ばずに dictionary のみから candidates を返す
+    ///
+    /// `google_timing` などの設定から Google Japanese Input を呼ぶ必要がある場合は `None` を
+    /// 返す。その場合は worker thread で `read_candidates()` を呼ぶこと。
+    ///
+    /// `Some` の場合の戻り値は `read_candidates()` と同じ形式。
+    pub(in crate::skk) fn read_candidates_without_google(
+        &self,
+        dictionary_file: &mut DictionaryFile,
+        midashi_buffer: &[u8],
+        sources: &mut AccessLogSources,
+    ) -> Result<Option<Vec<u8>>, SkkError> {
+        let config = self.get_config();
+        if config.google_timing == GoogleTiming::First || config.google_timing == GoogleTiming::Last
+        {
+            return Ok(None);
+        }
+        let on_memory = self.get_on_memory(dictionary_file)?;
+        let mut result = Vec::with_capacity(RESULT_VEC_CAPACITY);
+        result.push(b'1');
+        let midashi = Self::get_midashi(midashi_buffer);
+        let dictionary_midashi_key = Dictionary::get_dictionary_midashi_key(&midashi_buffer[1..])?;
+        Self::read_dictionary_candidates(
+            &on_memory,
+            dictionary_file,
+            midashi,
+            dictionary_midashi_key,
+            &mut result,
+        )?;
+        sources.dictionary = !Yaskkserv2::is_empty_candidates(&result);
+        self.statistics.count_dictionary(sources.dictionary);
+        if config.google_timing == GoogleTiming::NotFound
+            && Yaskkserv2::is_empty_candidates(&result)
+        {
+            return Ok(None);
         }
+        Ok(Some(result))
+    }
+
+    fn read_dictionary_candidates(
+        on_memory: &OnMemory,
+        dictionary_file: &mut DictionaryFile,
+        midashi: &[u8],
+        dictionary_midashi_key: DictionaryMidashiKey,
+        result: &mut Vec<u8>,
+    ) -> Result<(), SkkError> {
         if let Some(block_information_vectors) =
-            self.get_block_information_vectors(dictionary_midashi_key)
+            Self::get_block_information_vectors(on_memory, dictionary_midashi_key)
         {
             Self::read_dictionary_for_read_candidates(
                 midashi,
                 block_information_vectors,
-                self.on_memory.dictionary_fixed_header.blocks_offset,
+                on_memory.dictionary_fixed_header.blocks_offset,
                 dictionary_file,
-                &mut result,
+                result,
             )?;
+            Yaskkserv2::log_debug(|| {
+                format!(
+                    "dictionary blocks={} result length={}",
+                    block_information_vectors.len(),
+                    result.len()
+                )
+            });
+        } else {
+            Yaskkserv2::log_debug(|| String::from("dictionary blocks not found"));
         }
-        if self.config.google_timing == GoogleTiming::Last
-            || (self.config.google_timing == GoogleTiming::NotFound
-                && Yaskkserv2::is_empty_candidates(&result))
-        {
-            let _ignore_error_and_continue = self.read_google_candidates(midashi, &mut result);
-        }
-        Ok(result)
+        Ok(())
     }
 
     /// `midashi_buffer` にある midashi から対応する midashi 群を返す
@@ -79,21 +289,22 @@ impl DictionaryReader {
         dictionary_file: &mut DictionaryFile,
         midashi_buffer: &[u8],
     ) -> Result<Vec<u8>, SkkError> {
+        let on_memory = self.get_on_memory(dictionary_file)?;
         let mut result = Vec::with_capacity(RESULT_VEC_CAPACITY);
         result.push(b'1');
         let midashi = Self::get_midashi(midashi_buffer);
         let dictionary_midashi_key = Dictionary::get_dictionary_midashi_key(&midashi_buffer[1..])?;
         if let Some(block_information_vectors) =
-            self.get_block_information_vectors(dictionary_midashi_key)
+            Self::get_block_information_vectors(&on_memory, dictionary_midashi_key)
         {
             Self::read_dictionary_for_read_abbrev(
                 midashi,
                 block_information_vectors,
-                self.on_memory.dictionary_fixed_header.blocks_offset,
+                on_memory.dictionary_fixed_header.blocks_offset,
                 dictionary_file,
                 &mut result,
-                self.config.max_server_completions as usize,
-                Encoding::from_u32(self.on_memory.dictionary_fixed_header.encoding),
+                self.get_config().max_server_completions as usize,
+                Encoding::from_u32(on_memory.dictionary_fixed_header.encoding),
             )?;
         }
         if result.len() > 1 {
@@ -136,73 +347,85 @@ impl DictionaryReader {
     }
 
     fn get_block_information_vectors(
-        &self,
+        on_memory: &OnMemory,
         dictionary_midashi_key: DictionaryMidashiKey,
     ) -> Option<&[DictionaryBlockInformation]> {
         OnMemory::get_ascii_hiragana_vec_index(dictionary_midashi_key).map_or_else(
             || {
-                if self
-                    .on_memory
-                    .index_map
-                    .contains_key(&dictionary_midashi_key)
-                {
-                    Some(self.on_memory.index_map[&dictionary_midashi_key].as_slice())
+                if on_memory.index_map.contains_key(&dictionary_midashi_key) {
+                    Some(on_memory.index_map[&dictionary_midashi_key].as_slice())
                 } else {
                     None
                 }
             },
             |index| {
-                if self.on_memory.index_ascii_hiragana_vec[index].is_empty() {
+                if on_memory.index_ascii_hiragana_vec[index].is_empty() {
                     None
                 } else {
-                    Some(&self.on_memory.index_ascii_hiragana_vec[index])
+                    Some(&on_memory.index_ascii_hiragana_vec[index])
                 }
             },
         )
     }
 
-    fn read_google_candidates(&self, midashi: &[u8], result: &mut Vec<u8>) -> Result<(), SkkError> {
+    fn read_google_candidates(
+        &self,
+        config: &Config,
+        on_memory: &OnMemory,
+        midashi: &[u8],
+        result: &mut Vec<u8>,
+        sources: &mut AccessLogSources,
+    ) -> Result<(), SkkError> {
         let utf8_midashi = encoding_simple::Euc::decode(midashi).map_err(|e| {
             Yaskkserv2::log_error(&format!("{e}"));
             e
         })?;
-        let cached_google_utf8_candidates = if self.config.is_google_cache_enabled {
-            GoogleCache::get_candidates(&utf8_midashi)
+        // midashi は log に出力せず、数えるのみ
+        if !self.google_filter.is_allowed(&utf8_midashi) {
+            sources.google_blocked = true;
+            self.statistics
+                .google_blocked
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(SkkError::RequestSkipped);
+        }
+        let cached_google_utf8_candidates = if config.is_google_cache_enabled {
+            let cached_google_utf8_candidates = GoogleCache::get_candidates(&utf8_midashi);
+            self.statistics
+                .count_google_cache(!cached_google_utf8_candidates.is_empty());
+            cached_google_utf8_candidates
         } else {
             Vec::new()
         };
+        let mut google_sources = AccessLogSources::default();
         let google_utf8_candidates = if !cached_google_utf8_candidates.is_empty() {
+            google_sources.google_cache = true;
             cached_google_utf8_candidates
-        } else if self.config.is_google_suggest_enabled {
-            let mut tmp_candidates: Vec<Vec<u8>> = Request::request_google_japanese_input(
-                &self.google_japanese_input_protocol,
-                &utf8_midashi,
-                self.config.google_timeout_milliseconds,
-                self.config.google_max_candidates_length,
-                self.config.google_insert_hiragana_only_candidate,
-                self.config.google_insert_katakana_only_candidate,
-                self.config.google_insert_hankaku_katakana_only_candidate,
-            )
-            .unwrap_or_default();
-            tmp_candidates.extend(
-                Request::request_google_suggest(
-                    &self.google_suggest_protocol,
-                    &utf8_midashi,
-                    self.config.google_timeout_milliseconds,
-                )
-                .unwrap_or_default(),
-            );
+        } else if config.is_google_suggest_enabled {
+            let client = self.get_google_client(config)?;
+            let mut tmp_candidates: Vec<Vec<u8>> = self
+                .request_google_japanese_input(&client, config, &utf8_midashi)
+                .unwrap_or_default();
+            google_sources.google_japanese_input = !tmp_candidates.is_empty();
+            let suggest_candidates = self
+                .statistics
+                .google_suggest
+                .measure(|| {
+                    Request::request_google_suggest(
+                        &client,
+                        &self.google_limiter,
+                        &self.google_suggest_url,
+                        &utf8_midashi,
+                    )
+                })
+                .unwrap_or_default();
+            google_sources.google_suggest = !suggest_candidates.is_empty();
+            tmp_candidates.extend(suggest_candidates);
             Candidates::remove_duplicates(&tmp_candidates)
         } else {
-            let tmp_candidates = Request::request_google_japanese_input(
-                &self.google_japanese_input_protocol,
-                &utf8_midashi,
-                self.config.google_timeout_milliseconds,
-                self.config.google_max_candidates_length,
-                self.config.google_insert_hiragana_only_candidate,
-                self.config.google_insert_katakana_only_candidate,
-                self.config.google_insert_hankaku_katakana_only_candidate,
-            )?;
+            let client = self.get_google_client(config)?;
+            let tmp_candidates =
+                self.request_google_japanese_input(&client, config, &utf8_midashi)?;
+            google_sources.google_japanese_input = !tmp_candidates.is_empty();
             Candidates::remove_duplicates(&tmp_candidates)
         };
         if google_utf8_candidates.is_empty() {
@@ -217,8 +440,7 @@ impl DictionaryReader {
                 .collect::<Vec<u8>>();
             new_result.push(b'1');
             let new_candidates_bytes = Candidates::trim_one_slash(&new_candidates_bytes_tmp);
-            if Encoding::from_u32(self.on_memory.dictionary_fixed_header.encoding) == Encoding::Euc
-            {
+            if Encoding::from_u32(on_memory.dictionary_fixed_header.encoding) == Encoding::Euc {
                 match encoding_simple::Euc::encode(new_candidates_bytes) {
                     Ok(encoded) => {
                         new_result.extend_from_slice(&Candidates::merge_trimmed_slash_candidates(
@@ -239,18 +461,55 @@ impl DictionaryReader {
             }
         }
         *result = new_result;
-        if self.config.is_google_cache_enabled {
+        sources.google_cache = google_sources.google_cache;
+        sources.google_japanese_input = google_sources.google_japanese_input;
+        sources.google_suggest = google_sources.google_suggest;
+        if config.is_google_cache_enabled {
             GoogleCache::write_candidates(
                 &utf8_midashi,
                 &google_utf8_candidates,
-                &self.config.google_cache_full_path,
-                self.config.google_cache_entries,
-                self.config.google_cache_expire_seconds,
+                &config.google_cache_full_path,
+                config.google_cache_entries,
+                config.google_cache_expire_seconds,
             )?;
         }
         Ok(())
     }
 
+    /// 共有している client を返す。まだ無い場合は `config` から作成する
+    ///
+    /// 作成に失敗した場合は保持せず、次の request で作成し直す。
+    fn get_google_client(&self, config: &Config) -> Result<reqwest::blocking::Client, SkkError> {
+        let mut google_client = self.google_client.lock().unwrap();
+        if let Some(client) = google_client.as_ref() {
+            return Ok(client.clone());
+        }
+        let client = Request::create_client(config)?;
+        *google_client = Some(client.clone());
+        drop(google_client);
+        Ok(client)
+    }
+
+    fn request_google_japanese_input(
+        &self,
+        client: &reqwest::blocking::Client,
+        config: &Config,
+        utf8_midashi: &[u8],
+    ) -> Result<Vec<Vec<u8>>, SkkError> {
+        self.statistics.google_japanese_input.measure(|| {
+            Request::request_google_japanese_input(
+                client,
+                &self.google_limiter,
+                &self.google_japanese_input_url,
+                utf8_midashi,
+                config.google_max_candidates_length,
+                config.google_insert_hiragana_only_candidate,
+                config.google_insert_katakana_only_candidate,
+                config.google_insert_hankaku_katakana_only_candidate,
+            )
+        })
+    }
+
     /// `dictionary_block_informations` の探索 loop を開始するのに適した index を返す
     ///
     /// あくまでも loop 開始に適した index で、 index に目的の midashi が含まれるわけではない
@@ -3,25 +3,27 @@ use std::fs::File;
 use std::io::{Read, Write};
 
 use crate::skk::yaskkserv2::{
-    GoogleCache, GoogleCacheBTreeMap, SkkError, GOOGLE_CACHE_OBJECT, SHA1SUM_LENGTH,
+    GoogleCache, GoogleCacheBTreeMap, SkkError, GOOGLE_CACHE_FILE_MUTEX_LOCK, GOOGLE_CACHE_OBJECT,
+    SHA1SUM_LENGTH,
 };
 
 impl GoogleCache {
     pub(in crate::skk) fn get_candidates(midashi: &[u8]) -> Vec<Vec<u8>> {
-        let mut cached_candidates: Vec<Vec<u8>> = Vec::new();
-        if GOOGLE_CACHE_OBJECT
+        GOOGLE_CACHE_OBJECT
             .read()
             .unwrap()
             .map
-            .contains_key(midashi)
-            && GOOGLE_CACHE_OBJECT.read().unwrap().map[midashi].len() >= 2
-        {
-            cached_candidates.clone_from(&GOOGLE_CACHE_OBJECT.read().unwrap().map[midashi]);
-            cached_candidates.remove(0);
-        }
-        cached_candidates
+            .get(midashi)
+            .filter(|cached_candidates| cached_candidates.len() >= 2)
+            .map_or_else(Vec::new, |cached_candidates| {
+                cached_candidates[1..].to_vec()
+            })
     }
 
+    /// `midashi` の `candidates` を cache に追加して `cache_full_path` に書き込む
+    ///
+    /// Google worker の thread から同時に呼ばれるので、 cache の更新は 1 度の lock の中で行う
+    /// こと。
     pub(in crate::skk) fn write_candidates(
         midashi: &[u8],
         candidates: &[Vec<u8>],
@@ -29,53 +31,42 @@ impl GoogleCache {
         cache_entries: usize,
         cache_expire_seconds: u64,
     ) -> Result<(), SkkError> {
-        let should_write = if GOOGLE_CACHE_OBJECT
-            .write()
-            .unwrap()
-            .map
-            .contains_key(midashi)
-        {
-            candidates.to_vec() != GOOGLE_CACHE_OBJECT.write().unwrap().map[midashi]
-        } else {
-            true
-        };
-        if should_write {
+        // 古い map で新しい map を上書きしないよう、 map の更新から書き込みまで file の lock を
+        // 取ることに注意
+        let _file_lock = GOOGLE_CACHE_FILE_MUTEX_LOCK.lock().unwrap();
+        let map = {
+            let mut cache = GOOGLE_CACHE_OBJECT.write().unwrap();
+            if cache
+                .map
+                .get(midashi)
+                .is_some_and(|cached_candidates| cached_candidates.as_slice() == candidates)
+            {
+                return Ok(());
+            }
             let mut cache_candidates = candidates.to_owned();
             let unix_time_now = std::time::SystemTime::now()
                 .duration_since(std::time::SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
             cache_candidates.insert(0, unix_time_now.to_string().as_bytes().to_vec());
-            GOOGLE_CACHE_OBJECT
-                .write()
-                .unwrap()
-                .map
-                .insert(midashi.to_vec(), cache_candidates);
-            let mut expired_map: GoogleCacheBTreeMap = GOOGLE_CACHE_OBJECT
-                .write()
-                .unwrap()
-                .map
-                .clone()
-                .into_iter()
-                .filter(|(_k, v)| {
-                    let time: u64 = Self::parse_or_zero_u64(&v[0]);
-                    time > unix_time_now - cache_expire_seconds
-                })
-                .collect();
-            if expired_map.keys().len() > cache_entries {
-                let min = expired_map.clone().into_iter().min_by(|a, b| {
-                    let au: u64 = Self::parse_or_zero_u64(&a.1[0]);
-                    let bu: u64 = Self::parse_or_zero_u64(&b.1[0]);
-                    au.cmp(&bu)
-                });
+            cache.map.insert(midashi.to_vec(), cache_candidates);
+            cache.map.retain(|_k, v| {
+                let time: u64 = Self::parse_or_zero_u64(&v[0]);
+                time > unix_time_now.saturating_sub(cache_expire_seconds)
+            });
+            if cache.map.len() > cache_entries {
+                let min = cache
+                    .map
+                    .iter()
+                    .min_by_key(|(_k, v)| Self::parse_or_zero_u64(&v[0]))
+                    .map(|(k, _v)| k.clone());
                 if let Some(m) = min {
-                    expired_map.remove(&m.0);
+                    cache.map.remove(&m);
                 }
             }
-            GOOGLE_CACHE_OBJECT.write().unwrap().map = expired_map.clone();
-            Self::write(cache_full_path, &expired_map)?;
-        }
-        Ok(())
+            cache.map.clone()
+        };
+        Self::write(cache_full_path, &map)
     }
 
     pub(in crate::skk) fn setup_use_rwlock_internally(
@@ -91,6 +82,34 @@ impl GoogleCache {
         Ok(())
     }
 
+    /// cache の entry 数を返す
+    pub(in crate::skk) fn len() -> usize {
+        GOOGLE_CACHE_OBJECT.read().unwrap().map.len()
+    }
+
+    /// memory 上の cache を `cache_full_path` に書き込む
+    pub(in crate::skk) fn flush(cache_full_path: &str) -> Result<(), SkkError> {
+        let _file_lock = GOOGLE_CACHE_FILE_MUTEX_LOCK.lock().unwrap();
+        let map = GOOGLE_CACHE_OBJECT.read().unwrap().map.clone();
+        Self::write(cache_full_path, &map)
+    }
+
+    /// `midashi` の entry を cache から削除して `cache_full_path` に書き込む
+    ///
+    /// entry が無かった場合は書き込まずに false を返す。
+    pub(in crate::skk) fn remove(midashi: &[u8], cache_full_path: &str) -> Result<bool, SkkError> {
+        let _file_lock = GOOGLE_CACHE_FILE_MUTEX_LOCK.lock().unwrap();
+        let map = {
+            let mut cache = GOOGLE_CACHE_OBJECT.write().unwrap();
+            if cache.map.remove(midashi).is_none() {
+                return Ok(false);
+            }
+            cache.map.clone()
+        };
+        Self::write(cache_full_path, &map)?;
+        Ok(true)
+    }
+
     pub(in crate::skk) fn read(cache_full_path: &str) -> Result<GoogleCacheBTreeMap, SkkError> {
         let Ok(mut file) = File::open(cache_full_path) else {
             return Err(SkkError::CacheOpen);
@@ -127,15 +146,27 @@ impl GoogleCache {
             .unwrap_or(0)
     }
 
+    /// `map` を `cache_full_path` に書き込む
+    ///
+    /// 書き込み途中の file を読まれないよう、一時 file に書き込んでから rename する。一時 file
+    /// は upgrade 中の別の process と重ならないよう pid を付け、同じ process の中では
+    /// `GOOGLE_CACHE_FILE_MUTEX_LOCK` を取ってから呼ぶこと。
     fn write(cache_full_path: &str, map: &GoogleCacheBTreeMap) -> Result<(), SkkError> {
-        let mut file = File::create(cache_full_path)?;
+        let temporary_full_path = format!("{cache_full_path}.{}.tmp", std::process::id());
         let serialized_map = Self::serialize(map)?;
         let mut hasher = Sha1::new();
         hasher.update(&serialized_map);
         let digest: [u8; SHA1SUM_LENGTH] = hasher.finalize().as_slice().try_into().unwrap();
-        file.write_all(&digest)?;
-        file.write_all(&serialized_map)?;
-        Ok(())
+        let result = File::create(&temporary_full_path)
+            .and_then(|mut file| {
+                file.write_all(&digest)?;
+                file.write_all(&serialized_map)
+            })
+            .and_then(|()| std::fs::rename(&temporary_full_path, cache_full_path));
+        if result.is_err() {
+            let _ignore_error = std::fs::remove_file(&temporary_full_path);
+        }
+        Ok(result?)
     }
 }
 
@@ -144,6 +175,7 @@ mod test_unix {
     use crate::skk::test_unix::{read_jisyo_entries_no_encoding_conversion, setup, Path};
     use crate::skk::yaskkserv2::{GoogleCache, GoogleCacheBTreeMap};
     use crate::skk::Encoding;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     const LOOP: usize = 50;
 
@@ -221,6 +253,55 @@ mod test_unix {
         setup::exit();
     }
 
+    // 複数の thread から同時に cache を更新、削除しても panic せず、書き込み途中の file を
+    // 読まないかの test
+    #[test]
+    fn cache_write_candidates_threads_test() {
+        const THREADS: usize = 4;
+        const ENTRIES: usize = 50;
+        let name = "cache_write_candidates_threads_test";
+        setup::setup_and_wait(name);
+        let cache_full_path = Path::get_full_path(&format!("{name}.cache"));
+        GoogleCache::write(&cache_full_path, &get_huge_cache_b_tree_map()).unwrap();
+        let is_exit = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !is_exit.load(Ordering::SeqCst) {
+                    GoogleCache::read(&cache_full_path).unwrap();
+                }
+            });
+            let writer_handles = (0..THREADS)
+                .map(|thread_index| {
+                    let cache_full_path = &cache_full_path;
+                    scope.spawn(move || {
+                        for entry_index in 0..ENTRIES {
+                            let midashi = format!("{name}{thread_index}_{entry_index}");
+                            let candidates = vec![midashi.as_bytes().to_vec()];
+                            GoogleCache::write_candidates(
+                                midashi.as_bytes(),
+                                &candidates,
+                                cache_full_path,
+                                THREADS * ENTRIES,
+                                60,
+                            )
+                            .unwrap();
+                            GoogleCache::get_candidates(midashi.as_bytes());
+                            if entry_index % 2 == 0 {
+                                GoogleCache::remove(midashi.as_bytes(), cache_full_path).unwrap();
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in writer_handles {
+                handle.join().unwrap();
+            }
+            is_exit.store(true, Ordering::SeqCst);
+        });
+        GoogleCache::read(&cache_full_path).unwrap();
+        setup::exit();
+    }
+
     #[test]
     fn cache_write_huge_test() {
         let name = "cache_write_huge_test";
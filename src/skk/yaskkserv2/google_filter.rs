@@ -0,0 +1,92 @@
+use crate::skk::yaskkserv2::{Config, GoogleFilter, GoogleFilterRules, Regex, RwLock, Yaskkserv2};
+
+impl GoogleFilter {
+    pub(in crate::skk) fn new(config: &Config) -> Self {
+        Self {
+            rules: RwLock::new(GoogleFilterRules::new(config)),
+        }
+    }
+
+    /// `config` の条件に差し替える
+    pub(in crate::skk) fn update_config(&self, config: &Config) {
+        *self.rules.write().unwrap() = GoogleFilterRules::new(config);
+    }
+
+    /// UTF-8 の `midashi` を Google に送ってよいかを返す
+    ///
+    /// midashi は個人情報を含むことがあるので、拒否した場合も log には出力しないこと。
+    pub(in crate::skk) fn is_allowed(&self, midashi: &[u8]) -> bool {
+        let Ok(midashi) = std::str::from_utf8(midashi) else {
+            return false;
+        };
+        let rules = self.rules.read().unwrap();
+        if rules.is_all_denied {
+            return false;
+        }
+        let length = midashi.chars().count();
+        if length < rules.minimum_length
+            || (rules.maximum_length > 0 && length > rules.maximum_length)
+        {
+            return false;
+        }
+        if rules.is_okuri_ari_denied && Self::is_okuri_ari(midashi) {
+            return false;
+        }
+        if rules.is_digits_denied && midashi.chars().any(char::is_numeric) {
+            return false;
+        }
+        if rules
+            .allow
+            .as_ref()
+            .is_some_and(|allow| !allow.is_match(midashi))
+        {
+            return false;
+        }
+        !rules
+            .deny
+            .as_ref()
+            .is_some_and(|deny| deny.is_match(midashi))
+    }
+
+    /// `おくr` のように、 ASCII 以外の文字の後に送り仮名の小文字の alphabet が 1 文字続くか
+    /// どうかを返す
+    ///
+    /// `abbrev` のように全て ASCII の midashi は送りなしとみなす。
+    fn is_okuri_ari(midashi: &str) -> bool {
+        let mut reversed = midashi.chars().rev();
+        matches!(
+            (reversed.next(), reversed.next()),
+            (Some(okuri), Some(previous)) if okuri.is_ascii_lowercase() && !previous.is_ascii()
+        )
+    }
+}
+
+impl GoogleFilterRules {
+    fn new(config: &Config) -> Self {
+        let mut is_all_denied = false;
+        let mut compile = |key: &str, regex: &str| {
+            if regex.is_empty() {
+                return None;
+            }
+            Regex::new(regex).map_or_else(
+                |e| {
+                    Yaskkserv2::log_error(&format!("{key} error={e}"));
+                    is_all_denied = true;
+                    None
+                },
+                Some,
+            )
+        };
+        let allow = compile("google-allow-regex", &config.google_allow_regex);
+        let deny = compile("google-deny-regex", &config.google_deny_regex);
+        Self {
+            allow,
+            deny,
+            is_all_denied,
+            minimum_length: config.google_minimum_midashi_length,
+            maximum_length: config.google_maximum_midashi_length,
+            is_okuri_ari_denied: config.google_deny_okuri_ari,
+            is_digits_denied: config.google_deny_digits,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::skk::yaskkserv2::{
+    Config, Duration, GoogleLimiter, GoogleLimiterState, Instant, Mutex, SkkError, Yaskkserv2,
+    GOOGLE_LIMITER_TOKEN,
+};
+
+impl GoogleLimiter {
+    pub(in crate::skk) fn new(config: &Config) -> Self {
+        let mut state = GoogleLimiterState {
+            circuit_breaker_failures: 0,
+            circuit_breaker_cooldown: Duration::ZERO,
+            requests_per_minute: 0,
+            consecutive_failures: 0,
+            opened: None,
+            tokens: 0,
+            refilled: Instant::now(),
+        };
+        state.update_config(config);
+        state.tokens = state.get_max_tokens();
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// `config` の circuit breaker と request 数の制限の設定を反映する
+    ///
+    /// circuit breaker の状態と残りの token はそのまま引き継ぐ。
+    pub(in crate::skk) fn update_config(&self, config: &Config) {
+        let mut state = self.state.lock().unwrap();
+        state.update_config(config);
+        state.tokens = state.tokens.min(state.get_max_tokens());
+    }
+
+    /// request してよいかを返す
+    ///
+    /// circuit breaker が open で cooldown 中の場合と、 request 数の制限を越える場合は
+    /// `SkkError::RequestSkipped` を返す。 cooldown が過ぎた後は 1 つの request のみ試させ、
+    /// その結果が `record()` されるまでは再び cooldown 中として扱う。
+    pub(in crate::skk) fn acquire(&self) -> Result<(), SkkError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if let Some(opened) = state.opened {
+            if now.duration_since(opened) < state.circuit_breaker_cooldown {
+                return Err(SkkError::RequestSkipped);
+            }
+        }
+        if state.requests_per_minute > 0 {
+            let elapsed_milliseconds =
+                u64::try_from(now.duration_since(state.refilled).as_millis()).unwrap_or(u64::MAX);
+            state.tokens = state
+                .tokens
+                .saturating_add(
+                    elapsed_milliseconds.saturating_mul(u64::from(state.requests_per_minute)),
+                )
+                .min(state.get_max_tokens());
+            state.refilled = now;
+            if state.tokens < GOOGLE_LIMITER_TOKEN {
+                return Err(SkkError::RequestSkipped);
+            }
+            state.tokens -= GOOGLE_LIMITER_TOKEN;
+        }
+        if state.opened.is_some() {
+            state.opened = Some(now);
+        }
+        drop(state);
+        Ok(())
+    }
+
+    /// `acquire()` の後に行った request の結果を記録する
+    ///
+    /// `circuit_breaker_failures` 回続けて失敗すると circuit breaker を open にし、成功すると
+    /// close にする。
+    pub(in crate::skk) fn record(&self, is_success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if is_success {
+            if state.opened.is_some() {
+                Yaskkserv2::log_info("google circuit breaker closed");
+            }
+            state.consecutive_failures = 0;
+            state.opened = None;
+            return;
+        }
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.circuit_breaker_failures > 0
+            && state.consecutive_failures >= state.circuit_breaker_failures
+        {
+            if state.opened.is_none() {
+                Yaskkserv2::log_warn(&format!(
+                    "google circuit breaker opened for {} seconds after {} consecutive failures",
+                    state.circuit_breaker_cooldown.as_secs(),
+                    state.consecutive_failures
+                ));
+            }
+            state.opened = Some(Instant::now());
+        }
+    }
+}
+
+impl GoogleLimiterState {
+    const fn update_config(&mut self, config: &Config) {
+        self.circuit_breaker_failures = config.google_circuit_breaker_failures;
+        self.circuit_breaker_cooldown =
+            Duration::from_secs(config.google_circuit_breaker_cooldown_seconds);
+        self.requests_per_minute = config.google_requests_per_minute;
+        if self.circuit_breaker_failures == 0 {
+            self.opened = None;
+        }
+    }
+
+    /// 1 分間の request 数を上限として、それ以上は貯めない
+    fn get_max_tokens(&self) -> u64 {
+        u64::from(self.requests_per_minute) * GOOGLE_LIMITER_TOKEN
+    }
+}
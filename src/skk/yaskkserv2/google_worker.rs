@@ -0,0 +1,78 @@
+use std::panic::AssertUnwindSafe;
+
+use crate::skk::yaskkserv2::{
+    mpsc, DictionaryFile, File, GoogleWorker, GoogleWorkerJob, GoogleWorkerResult, Mutex, Server,
+    TcpStreamSkk, Waker, Yaskkserv2, INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH,
+};
+
+impl<'a> GoogleWorker<'a> {
+    pub(in crate::skk) const fn new(
+        server: &'a Server,
+        job_receiver: &'a Mutex<mpsc::Receiver<GoogleWorkerJob>>,
+        result_senders: Vec<mpsc::Sender<GoogleWorkerResult>>,
+        wakers: &'a [Waker],
+    ) -> Self {
+        Self {
+            server,
+            job_receiver,
+            result_senders,
+            wakers,
+        }
+    }
+
+    /// job sender が drop されるまで job を処理し続ける
+    ///
+    /// 結果は job を送ってきた poll loop (`loop_index`) へ返す。
+    ///
+    /// dictionary の seek 位置などを poll loop と共有できないので、 `DictionaryFile` は worker
+    /// ごとに open することに注意。
+    pub(in crate::skk) fn run(self) {
+        let mut dictionary_file = match File::open(&self.server.config.dictionary_full_path) {
+            Ok(file) => DictionaryFile::new(file, INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH),
+            Err(e) => {
+                Yaskkserv2::log_error(&format!("google worker dictionary open failed {e}"));
+                return;
+            }
+        };
+        loop {
+            let job = {
+                let Ok(job_receiver) = self.job_receiver.lock() else {
+                    return;
+                };
+                match job_receiver.recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                }
+            };
+            let loop_index = job.loop_index;
+            let mut buffer = job.buffer;
+            let mut access_log_entry = job.access_log_entry;
+            // 結果を返さないと poll loop の connection が結果待ちのまま残るので、 panic した
+            // 場合も error を返す
+            let response = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                self.server.handle_client_protocol_1_google(
+                    &mut dictionary_file,
+                    &mut buffer,
+                    &mut access_log_entry,
+                )
+            }))
+            .unwrap_or_else(|_| {
+                Yaskkserv2::log_error("google worker panicked");
+                let mut response = Vec::new();
+                let _ignore_error = response.write_error_flush();
+                response
+            });
+            let result = GoogleWorkerResult {
+                token: job.token,
+                serial: job.serial,
+                response,
+            };
+            if self.result_senders[loop_index].send(result).is_err() {
+                continue;
+            }
+            if let Err(e) = self.wakers[loop_index].wake() {
+                Yaskkserv2::log_error(&format!("google worker wake failed {e}"));
+            }
+        }
+    }
+}
@@ -0,0 +1,547 @@
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use crate::skk::yaskkserv2::{
+    Arc, Config, IpAddr, Listener, MioListener, MioStream, Read, Shutdown, SkkError, SocketAddr,
+    TcpListener, Write, Yaskkserv2,
+};
+#[cfg(unix)]
+use crate::skk::yaskkserv2::{
+    UnixListener, UpgradeHandover, UPGRADE_CONTROL_FD_ENV, UPGRADE_LISTENER_FDS_ENV,
+    UPGRADE_METRICS_FD_ENV, UPGRADE_READY_FD_ENV,
+};
+
+impl Listener {
+    /// config で指定された全ての listener を bind する
+    pub(in crate::skk) fn bind_all(config: &Config) -> Result<Vec<Self>, SkkError> {
+        let mut listeners = Vec::new();
+        for listen_address in &config.listen_addresses {
+            let Some(socket_addr) = Self::parse_listen_address(listen_address, &config.port) else {
+                return Err(SkkError::CommandLine);
+            };
+            listeners.push(Self::bind_tcp(socket_addr)?);
+        }
+        if !config.tls_port.is_empty() {
+            let Ok(tls_port) = config.tls_port.parse() else {
+                return Err(SkkError::CommandLine);
+            };
+            let tls_config = Self::get_tls_server_config(config)?;
+            for listen_address in &config.listen_addresses {
+                let Some(mut socket_addr) =
+                    Self::parse_listen_address(listen_address, &config.tls_port)
+                else {
+                    return Err(SkkError::CommandLine);
+                };
+                socket_addr.set_port(tls_port);
+                listeners.push(Self::bind_tls(socket_addr, Arc::clone(&tls_config))?);
+            }
+        }
+        #[cfg(unix)]
+        if !config.listen_unix_socket.is_empty() {
+            listeners.push(Self::bind_unix(
+                &config.listen_unix_socket,
+                config.listen_unix_socket_mode,
+            )?);
+        }
+        Ok(listeners)
+    }
+
+    /// `ADDR:PORT` はそのまま、 `ADDR` は `port` と組み合わせた `SocketAddr` を返す
+    pub(in crate::skk) fn parse_listen_address(
+        listen_address: &str,
+        port: &str,
+    ) -> Option<SocketAddr> {
+        if let Ok(socket_addr) = listen_address.parse::<SocketAddr>() {
+            return Some(socket_addr);
+        }
+        let ip_address = listen_address.parse().ok()?;
+        Some(SocketAddr::new(ip_address, port.parse().ok()?))
+    }
+
+    pub(in crate::skk) fn bind_tcp(socket_addr: SocketAddr) -> Result<Self, SkkError> {
+        let listener = std::net::TcpListener::bind(socket_addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self::Tcp(listener))
+    }
+
+    /// `socket_addr` で TLS の listener を bind する
+    ///
+    /// TLS の listen address は TCP と同じで、 port のみ `tls_port` に置き換える。
+    fn bind_tls(
+        socket_addr: SocketAddr,
+        tls_config: Arc<rustls::ServerConfig>,
+    ) -> Result<Self, SkkError> {
+        let listener = std::net::TcpListener::bind(socket_addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self::Tls {
+            listener,
+            tls_config,
+        })
+    }
+
+    /// protocol 3 で返す `"hostname:addr:[addr...:]"` を bind した listener から作成する
+    ///
+    /// unix domain socket は IP address を持たないので含まれない。
+    pub(in crate::skk) fn get_hostname_and_ip_address_for_protocol_3(listeners: &[Self]) -> String {
+        let mut ip_addresses = Vec::new();
+        for listener in listeners {
+            match listener {
+                Self::Tcp(listener) | Self::Tls { listener, .. } => {
+                    if let Ok(local_addr) = listener.local_addr() {
+                        if !ip_addresses.contains(&local_addr.ip()) {
+                            ip_addresses.push(local_addr.ip());
+                        }
+                    }
+                }
+                #[cfg(unix)]
+                Self::Unix { .. } => {}
+            }
+        }
+        let mut result = Self::get_hostname();
+        result.push(':');
+        for ip_address in ip_addresses {
+            result.push_str(&ip_address.to_string());
+            result.push(':');
+        }
+        result
+    }
+
+    #[cfg(unix)]
+    fn get_hostname() -> String {
+        let mut buffer = [0_u8; 256];
+        if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+            return String::from("localhost");
+        }
+        let length = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+        String::from_utf8_lossy(&buffer[..length]).into_owned()
+    }
+
+    #[cfg(not(unix))]
+    fn get_hostname() -> String {
+        String::from("localhost")
+    }
+
+    /// `full_path` に unix domain socket を作成し、 permission を `mode` に設定する
+    ///
+    /// 前回の実行で残った socket は削除してから作成する。 socket 以外の file が存在する場合は
+    /// `Err` を返す。 bind から `set_permissions()` までの間に他の user から connect されない
+    /// よう、 umask を一時的に 0o077 にして作成することに注意。
+    #[cfg(unix)]
+    pub(in crate::skk) fn bind_unix(full_path: &str, mode: u32) -> Result<Self, SkkError> {
+        if let Ok(metadata) = std::fs::symlink_metadata(full_path) {
+            if !metadata.file_type().is_socket() {
+                return Err(SkkError::Io(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{full_path} is not a socket"),
+                )));
+            }
+            std::fs::remove_file(full_path)?;
+        }
+        let old_umask = unsafe { libc::umask(0o077) };
+        let bind_result = std::os::unix::net::UnixListener::bind(full_path);
+        unsafe {
+            libc::umask(old_umask);
+        }
+        let listener = bind_result?;
+        std::fs::set_permissions(full_path, std::fs::Permissions::from_mode(mode))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self::Unix {
+            listener,
+            full_path: Some(String::from(full_path)),
+        })
+    }
+
+    /// `LISTEN_PID` が自 process の場合、 systemd から継承した fd を返す
+    ///
+    /// fd は `SD_LISTEN_FDS_START` (3) から `LISTEN_FDS` 個並んでいる。
+    #[cfg(unix)]
+    pub(in crate::skk) fn get_socket_activation_fds() -> Option<std::ops::Range<RawFd>> {
+        const SD_LISTEN_FDS_START: RawFd = 3;
+        let listen_pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+        if listen_pid != std::process::id() {
+            return None;
+        }
+        let listen_fds = std::env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+        if listen_fds <= 0 {
+            return None;
+        }
+        Some(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + listen_fds)
+    }
+
+    /// systemd から継承した listening socket を listener として返す
+    ///
+    /// 子 process へ継承しないよう、 `LISTEN_PID` などの環境変数は削除し、 fd には
+    /// `FD_CLOEXEC` を設定する。
+    #[cfg(unix)]
+    pub(in crate::skk) fn from_socket_activation() -> Result<Vec<Self>, SkkError> {
+        let Some(fds) = Self::get_socket_activation_fds() else {
+            return Err(SkkError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "LISTEN_FDS not found",
+            )));
+        };
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        let mut listeners = Vec::new();
+        for fd in fds {
+            Self::set_close_on_exec(fd)?;
+            let listener = if Self::is_unix_socket(fd)? {
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Self::Unix {
+                    listener,
+                    full_path: None,
+                }
+            } else {
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Self::Tcp(listener)
+            };
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// upgrade 元の process から継承した listening socket などを返す
+    ///
+    /// `YASKKSERV2_UPGRADE_LISTENER_FDS` は `tcp:FD,tls:FD,unix:FD` の形式。 TLS の証明書は
+    /// `config` から読み直し、終了時に削除する unix domain socket は `config` のものとする。
+    /// さらに upgrade する際に継承しないよう、環境変数は削除し、 fd には `FD_CLOEXEC` を設定する。
+    #[cfg(unix)]
+    pub(in crate::skk) fn from_upgrade(
+        config: &Config,
+    ) -> Result<(Vec<Self>, UpgradeHandover), SkkError> {
+        let listener_fds = std::env::var(UPGRADE_LISTENER_FDS_ENV).unwrap_or_default();
+        let control_fd = Self::get_upgrade_fd(UPGRADE_CONTROL_FD_ENV)?;
+        let metrics_fd = Self::get_upgrade_fd(UPGRADE_METRICS_FD_ENV)?;
+        let Some(ready_fd) = Self::get_upgrade_fd(UPGRADE_READY_FD_ENV)? else {
+            return Err(Self::get_upgrade_environment_error(UPGRADE_READY_FD_ENV));
+        };
+        for name in [
+            UPGRADE_LISTENER_FDS_ENV,
+            UPGRADE_CONTROL_FD_ENV,
+            UPGRADE_METRICS_FD_ENV,
+            UPGRADE_READY_FD_ENV,
+        ] {
+            std::env::remove_var(name);
+        }
+        for fd in [control_fd, metrics_fd, Some(ready_fd)]
+            .into_iter()
+            .flatten()
+        {
+            Self::set_close_on_exec(fd)?;
+        }
+        let mut listeners = Vec::new();
+        for kind_and_fd in listener_fds.split(',').filter(|value| !value.is_empty()) {
+            let Some((kind, Ok(fd))) = kind_and_fd
+                .split_once(':')
+                .map(|(kind, fd)| (kind, fd.parse::<RawFd>()))
+            else {
+                return Err(Self::get_upgrade_environment_error(
+                    UPGRADE_LISTENER_FDS_ENV,
+                ));
+            };
+            Self::set_close_on_exec(fd)?;
+            let listener = match kind {
+                "tcp" => Self::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+                "tls" => Self::Tls {
+                    listener: unsafe { std::net::TcpListener::from_raw_fd(fd) },
+                    tls_config: Self::get_tls_server_config(config)?,
+                },
+                "unix" => Self::Unix {
+                    listener: unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) },
+                    full_path: (!config.listen_unix_socket.is_empty())
+                        .then(|| config.listen_unix_socket.clone()),
+                },
+                _ => {
+                    return Err(Self::get_upgrade_environment_error(
+                        UPGRADE_LISTENER_FDS_ENV,
+                    ))
+                }
+            };
+            listeners.push(listener);
+        }
+        let control_listener = control_fd.map(|fd| Self::Unix {
+            listener: unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) },
+            full_path: (!config.control_socket.is_empty()).then(|| config.control_socket.clone()),
+        });
+        let metrics_listener =
+            metrics_fd.map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) });
+        let handover = UpgradeHandover {
+            control_listener,
+            metrics_listener,
+            ready: unsafe { std::fs::File::from_raw_fd(ready_fd) },
+        };
+        Ok((listeners, handover))
+    }
+
+    /// 環境変数 `name` の fd を返す。存在しない場合は `None` となる。
+    #[cfg(unix)]
+    fn get_upgrade_fd(name: &str) -> Result<Option<RawFd>, SkkError> {
+        let Ok(value) = std::env::var(name) else {
+            return Ok(None);
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| Self::get_upgrade_environment_error(name))
+    }
+
+    #[cfg(unix)]
+    fn get_upgrade_environment_error(name: &str) -> SkkError {
+        SkkError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{name} is invalid"),
+        ))
+    }
+
+    /// upgrade で新しい process に渡す種類と fd を返す
+    #[cfg(unix)]
+    pub(in crate::skk) fn get_upgrade_kind_and_fd(&self) -> (&'static str, RawFd) {
+        match self {
+            Self::Tcp(listener) => ("tcp", listener.as_raw_fd()),
+            Self::Tls { listener, .. } => ("tls", listener.as_raw_fd()),
+            Self::Unix { listener, .. } => ("unix", listener.as_raw_fd()),
+        }
+    }
+
+    /// 継承した fd が `exec()` する子 process へ漏れないよう `FD_CLOEXEC` を設定する
+    #[cfg(unix)]
+    fn set_close_on_exec(fd: RawFd) -> Result<(), std::io::Error> {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn is_unix_socket(fd: RawFd) -> Result<bool, std::io::Error> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        #[allow(clippy::cast_possible_truncation)]
+        let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe {
+            libc::getsockname(
+                fd,
+                std::ptr::addr_of_mut!(storage).cast::<libc::sockaddr>(),
+                std::ptr::addr_of_mut!(length),
+            )
+        } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(libc::c_int::from(storage.ss_family) == libc::AF_UNIX)
+    }
+
+    /// yaskkserv2 が作成した unix domain socket を削除する
+    pub(in crate::skk) fn remove_unix_socket(&self) {
+        match self {
+            Self::Tcp(_) | Self::Tls { .. } => {}
+            #[cfg(unix)]
+            Self::Unix { full_path, .. } => {
+                if let Some(full_path) = full_path {
+                    if let Err(e) = std::fs::remove_file(full_path) {
+                        Yaskkserv2::log_error(&format!("unix socket remove failed {e}"));
+                    }
+                }
+            }
+        }
+    }
+
+    /// poll loop の thread ごとに登録するため、 `try_clone()` したものを `MioListener` で返す
+    pub(in crate::skk) fn try_clone_mio(&self) -> Result<MioListener, std::io::Error> {
+        match self {
+            Self::Tcp(listener) => Ok(MioListener::Tcp(TcpListener::from_std(
+                listener.try_clone()?,
+            ))),
+            Self::Tls {
+                listener,
+                tls_config,
+            } => Ok(MioListener::Tls {
+                listener: TcpListener::from_std(listener.try_clone()?),
+                tls_config: Arc::clone(tls_config),
+            }),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => Ok(MioListener::Unix(UnixListener::from_std(
+                listener.try_clone()?,
+            ))),
+        }
+    }
+}
+
+impl MioListener {
+    pub(in crate::skk) fn accept(&self) -> Result<MioStream, std::io::Error> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| MioStream::Tcp(stream)),
+            Self::Tls {
+                listener,
+                tls_config,
+            } => {
+                let (stream, _) = listener.accept()?;
+                let connection = rustls::ServerConnection::new(Arc::clone(tls_config))
+                    .map_err(std::io::Error::other)?;
+                Ok(MioStream::Tls(Box::new(rustls::StreamOwned::new(
+                    connection, stream,
+                ))))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.accept().map(|(stream, _)| MioStream::Unix(stream)),
+        }
+    }
+}
+
+impl mio::event::Source for MioListener {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(listener) | Self::Tls { listener, .. } => {
+                listener.register(registry, token, interests)
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(listener) | Self::Tls { listener, .. } => {
+                listener.reregister(registry, token, interests)
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(listener) | Self::Tls { listener, .. } => listener.deregister(registry),
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+impl MioStream {
+    pub(in crate::skk) fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            Self::Tls(stream) => stream.sock.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// `allow-from` と `deny-from` で判定する接続元。 unix domain socket は `None` となる。
+    ///
+    /// TCP と TLS で接続元を取得できない場合は `Err` を返す。
+    pub(in crate::skk) fn peer_ip_address(&self) -> Result<Option<IpAddr>, std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().map(|addr| Some(addr.ip())),
+            Self::Tls(stream) => stream.sock.peer_addr().map(|addr| Some(addr.ip())),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(None),
+        }
+    }
+
+    /// TLS の場合、 rustls が暗号化済みで未送信の data を持っているかを返す
+    pub(in crate::skk) fn is_write_pending(&self) -> bool {
+        match self {
+            Self::Tls(stream) => stream.conn.wants_write(),
+            _ => false,
+        }
+    }
+
+    /// log 用の接続元。 unix domain socket の client は通常名前を持たないので `"unix"` となる。
+    pub(in crate::skk) fn peer_addr(&self) -> Result<String, std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().map(|addr| addr.to_string()),
+            Self::Tls(stream) => stream.sock.peer_addr().map(|addr| addr.to_string()),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.peer_addr().map(|addr| {
+                addr.as_pathname()
+                    .map_or_else(|| String::from("unix"), |path| path.display().to_string())
+            }),
+        }
+    }
+}
+
+impl Read for MioStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MioStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl mio::event::Source for MioStream {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.register(registry, token, interests),
+            Self::Tls(stream) => stream.sock.register(registry, token, interests),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.reregister(registry, token, interests),
+            Self::Tls(stream) => stream.sock.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.deregister(registry),
+            Self::Tls(stream) => stream.sock.deregister(registry),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+#[cfg(all(not(test), unix))]
+use crate::skk::yaskkserv2::Yaskkserv2;
+#[cfg(all(not(test), not(unix)))]
+use crate::skk::yaskkserv2::{debug, error, info, warn};
+use crate::skk::yaskkserv2::{
+    AccessLog, Config, LogLevel, LogTarget, LogWriter, Logger, SkkError, SystemTime, Write, LOGGER,
+};
+
+const LOG_TARGET_FILE_PREFIX: &str = "file:";
+
+impl Logger {
+    pub(in crate::skk) const fn new() -> Self {
+        Self {
+            level: LogLevel::Info,
+            #[cfg(all(not(test), unix))]
+            writer: LogWriter::Syslog(None),
+            #[cfg(any(test, not(unix)))]
+            writer: LogWriter::Syslog,
+        }
+    }
+
+    /// `config` の `log_target` と `log_level` を設定する
+    pub(in crate::skk) fn setup(config: &Config) -> Result<(), SkkError> {
+        let writer = match &config.log_target {
+            #[cfg(all(not(test), unix))]
+            LogTarget::Syslog => LogWriter::Syslog(None),
+            #[cfg(any(test, not(unix)))]
+            LogTarget::Syslog => LogWriter::Syslog,
+            LogTarget::Stderr => LogWriter::Stderr,
+            LogTarget::File(full_path) => LogWriter::File(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(full_path)?,
+            ),
+        };
+        let mut logger = LOGGER.lock().unwrap();
+        logger.level = config.log_level;
+        logger.writer = writer;
+        drop(logger);
+        Ok(())
+    }
+
+    pub(in crate::skk) fn is_enabled(level: LogLevel) -> bool {
+        level <= LOGGER.lock().unwrap().level
+    }
+
+    pub(in crate::skk) fn log(level: LogLevel, message: &str) {
+        let mut logger = LOGGER.lock().unwrap();
+        if level <= logger.level {
+            logger.writer.write(level, message);
+        }
+    }
+
+    /// `syslog`, `stderr`, `file:PATH` を受け付ける
+    pub(in crate::skk) fn parse_target(value: &str) -> Option<LogTarget> {
+        match value {
+            "syslog" => Some(LogTarget::Syslog),
+            "stderr" => Some(LogTarget::Stderr),
+            _ => value
+                .strip_prefix(LOG_TARGET_FILE_PREFIX)
+                .filter(|full_path| !full_path.is_empty())
+                .map(|full_path| LogTarget::File(String::from(full_path))),
+        }
+    }
+
+    pub(in crate::skk) fn parse_level(value: &str) -> Option<LogLevel> {
+        match value {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl LogWriter {
+    /// 書き込みに失敗しても出力先が無いので、 stderr に出力するのみ
+    fn write(&mut self, level: LogLevel, message: &str) {
+        let label = match level {
+            LogLevel::Error => "Error",
+            LogLevel::Warn => "Warning",
+            LogLevel::Info => "Info",
+            LogLevel::Debug => "Debug",
+        };
+        match self {
+            #[cfg(all(not(test), unix))]
+            Self::Syslog(writer) => {
+                if writer.is_none() {
+                    match syslog::unix(Yaskkserv2::get_log_formatter()) {
+                        Ok(connected) => *writer = Some(connected),
+                        Err(e) => eprintln!("impossible to connect to syslog: {e:?}"),
+                    }
+                }
+                if let Some(connected) = writer.as_mut() {
+                    let message = String::from(message);
+                    let result = match level {
+                        LogLevel::Error => connected.err(message),
+                        LogLevel::Warn => connected.warning(message),
+                        LogLevel::Info => connected.info(message),
+                        LogLevel::Debug => connected.debug(message),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("could not send message to syslog: {e:?}");
+                        *writer = None;
+                    }
+                }
+            }
+            #[cfg(test)]
+            Self::Syslog => println!("{label}: {message}"),
+            #[cfg(all(not(test), not(unix)))]
+            Self::Syslog => match level {
+                LogLevel::Error => error!("{}", message),
+                LogLevel::Warn => warn!("{}", message),
+                LogLevel::Info => info!("{}", message),
+                LogLevel::Debug => debug!("{}", message),
+            },
+            Self::Stderr => eprintln!("{label}: {message}"),
+            Self::File(file) => {
+                let timestamp = AccessLog::format_timestamp(SystemTime::now());
+                if let Err(e) = writeln!(file, "{timestamp} {label}: {message}") {
+                    eprintln!("could not write log file: {e}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::fmt::Write as _;
+
+use crate::skk::yaskkserv2::{
+    AtomicU64, BufRead, BufReader, DeadlineStream, Events, GoogleCache, GoogleStatistics,
+    Histogram, Instant, Interest, Ordering, Poll, RunLoopShared, SkkError, Statistics, Token,
+    Write, Yaskkserv2, METRICS_LATENCY_BUCKETS, METRICS_TIMEOUT,
+};
+
+const METRICS_LISTENER: Token = Token(0);
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+impl Statistics {
+    pub(in crate::skk) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            accepted_connections: AtomicU64::new(0),
+            rejected_connections_access_control: AtomicU64::new(0),
+            rejected_connections_max_connections: AtomicU64::new(0),
+            protocol_requests: std::array::from_fn(|_| AtomicU64::new(0)),
+            dictionary_hits: AtomicU64::new(0),
+            dictionary_misses: AtomicU64::new(0),
+            google_requests: AtomicU64::new(0),
+            google_blocked: AtomicU64::new(0),
+            google_japanese_input: GoogleStatistics::new(),
+            google_suggest: GoogleStatistics::new(),
+            google_cache_hits: AtomicU64::new(0),
+            google_cache_misses: AtomicU64::new(0),
+        }
+    }
+
+    /// `protocol` (request の先頭の byte) ごとに request を数える
+    pub(in crate::skk) fn count_request(&self, protocol: u8) {
+        let index = match protocol {
+            b'0'..=b'4' => usize::from(protocol - b'0'),
+            _ => self.protocol_requests.len() - 1,
+        };
+        self.protocol_requests[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 全ての protocol の request 数を返す
+    pub(in crate::skk) fn get_requests(&self) -> u64 {
+        self.protocol_requests
+            .iter()
+            .map(|requests| requests.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub(in crate::skk) fn count_dictionary(&self, is_hit: bool) {
+        if is_hit {
+            self.dictionary_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.dictionary_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(in crate::skk) fn count_google_cache(&self, is_hit: bool) {
+        if is_hit {
+            self.google_cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.google_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Prometheus の text format で返す
+    #[allow(clippy::too_many_lines)]
+    fn get_metrics_text(&self, connections: usize) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let protocol_requests: Vec<(String, u64)> = self
+            .protocol_requests
+            .iter()
+            .enumerate()
+            .map(|(index, requests)| {
+                let protocol = if index + 1 == self.protocol_requests.len() {
+                    String::from("other")
+                } else {
+                    index.to_string()
+                };
+                (format!(r#"protocol="{protocol}""#), load(requests))
+            })
+            .collect();
+        let apis = [
+            ("japanese_input", &self.google_japanese_input),
+            ("suggest", &self.google_suggest),
+        ];
+        let api_values = |load_api: fn(&GoogleStatistics) -> u64| -> Vec<(String, u64)> {
+            apis.iter()
+                .map(|(api, statistics)| (format!(r#"api="{api}""#), load_api(statistics)))
+                .collect()
+        };
+        let hit_miss = |hits: &AtomicU64, misses: &AtomicU64| {
+            vec![
+                (String::from(r#"result="hit""#), load(hits)),
+                (String::from(r#"result="miss""#), load(misses)),
+            ]
+        };
+        let mut text = String::new();
+        for (name, metric_type, help, values) in [
+            (
+                "uptime_seconds",
+                "gauge",
+                "Seconds since start.",
+                vec![(String::new(), self.started.elapsed().as_secs())],
+            ),
+            (
+                "connections",
+                "gauge",
+                "Active connections.",
+                vec![(String::new(), connections as u64)],
+            ),
+            (
+                "accepted_connections_total",
+                "counter",
+                "Accepted connections.",
+                vec![(String::new(), load(&self.accepted_connections))],
+            ),
+            (
+                "rejected_connections_total",
+                "counter",
+                "Rejected connections by reason.",
+                vec![
+                    (
+                        String::from(r#"reason="access_control""#),
+                        load(&self.rejected_connections_access_control),
+                    ),
+                    (
+                        String::from(r#"reason="max_connections""#),
+                        load(&self.rejected_connections_max_connections),
+                    ),
+                ],
+            ),
+            (
+                "requests_total",
+                "counter",
+                "Requests by protocol.",
+                protocol_requests,
+            ),
+            (
+                "dictionary_lookups_total",
+                "counter",
+                "Protocol 1 dictionary lookups by result.",
+                hit_miss(&self.dictionary_hits, &self.dictionary_misses),
+            ),
+            (
+                "google_worker_requests_total",
+                "counter",
+                "Requests passed to the Google Japanese Input workers.",
+                vec![(String::new(), load(&self.google_requests))],
+            ),
+            (
+                "google_blocked_total",
+                "counter",
+                "Lookups not sent to Google by the midashi filter.",
+                vec![(String::new(), load(&self.google_blocked))],
+            ),
+            (
+                "google_calls_total",
+                "counter",
+                "Google API calls.",
+                api_values(|statistics| statistics.calls.load(Ordering::Relaxed)),
+            ),
+            (
+                "google_errors_total",
+                "counter",
+                "Google API calls failed except timeouts.",
+                api_values(|statistics| statistics.errors.load(Ordering::Relaxed)),
+            ),
+            (
+                "google_timeouts_total",
+                "counter",
+                "Google API calls timed out.",
+                api_values(|statistics| statistics.timeouts.load(Ordering::Relaxed)),
+            ),
+            (
+                "google_skipped_total",
+                "counter",
+                "Google API calls skipped by the circuit breaker or the requests per minute limit.",
+                api_values(|statistics| statistics.skipped.load(Ordering::Relaxed)),
+            ),
+            (
+                "google_cache_lookups_total",
+                "counter",
+                "Google Japanese Input cache lookups by result.",
+                hit_miss(&self.google_cache_hits, &self.google_cache_misses),
+            ),
+            (
+                "google_cache_entries",
+                "gauge",
+                "Google Japanese Input cache entries.",
+                vec![(String::new(), GoogleCache::len() as u64)],
+            ),
+        ] {
+            let _ignore_error = writeln!(text, "# HELP yaskkserv2_{name} {help}");
+            let _ignore_error = writeln!(text, "# TYPE yaskkserv2_{name} {metric_type}");
+            for (labels, value) in values {
+                if labels.is_empty() {
+                    let _ignore_error = writeln!(text, "yaskkserv2_{name} {value}");
+                } else {
+                    let _ignore_error = writeln!(text, "yaskkserv2_{name}{{{labels}}} {value}");
+                }
+            }
+        }
+        let name = "google_request_duration_seconds";
+        let _ignore_error = writeln!(text, "# HELP yaskkserv2_{name} Google API call latency.");
+        let _ignore_error = writeln!(text, "# TYPE yaskkserv2_{name} histogram");
+        for (api, statistics) in apis {
+            statistics.latency.write_metrics_text(
+                &mut text,
+                &format!("yaskkserv2_{name}"),
+                &format!(r#"api="{api}""#),
+            );
+        }
+        text
+    }
+}
+
+impl GoogleStatistics {
+    fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            latency: Histogram::new(),
+        }
+    }
+
+    /// `request` を呼び、呼び出し回数と latency と失敗を数える
+    ///
+    /// `SkkError::RequestSkipped` の場合は request していないので、 `skipped` のみ数える。
+    pub(in crate::skk) fn measure<T>(
+        &self,
+        request: impl FnOnce() -> Result<T, SkkError>,
+    ) -> Result<T, SkkError> {
+        let start = Instant::now();
+        let result = request();
+        if matches!(&result, Err(SkkError::RequestSkipped)) {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return result;
+        }
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(start.elapsed());
+        match &result {
+            Ok(_) => {}
+            Err(SkkError::Reqwest(e)) if e.is_timeout() => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_microseconds: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: std::time::Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = METRICS_LATENCY_BUCKETS
+            .iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+        {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        self.sum_microseconds
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn write_metrics_text(&self, text: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bucket, upper_bound) in self.buckets.iter().zip(METRICS_LATENCY_BUCKETS) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ignore_error = writeln!(
+                text,
+                r#"{name}_bucket{{{labels},le="{upper_bound}"}} {cumulative}"#
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ignore_error = writeln!(text, r#"{name}_bucket{{{labels},le="+Inf"}} {count}"#);
+        let sum = self.sum_microseconds.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ignore_error = writeln!(text, "{name}_sum{{{labels}}} {sum}");
+        let _ignore_error = writeln!(text, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+impl Yaskkserv2 {
+    /// `/metrics` への HTTP request に Prometheus の text format で統計を返す
+    ///
+    /// 1 つの request ごとに接続を閉じる。 SKK の client と同じく allow-from と deny-from で
+    /// 制限する。 `shared` の `exit()` か `shutdown()` か `drain()` で終了する。
+    pub(in crate::skk) fn run_metrics(
+        &self,
+        mut poll: Poll,
+        metrics_listener: &std::net::TcpListener,
+        shared: &RunLoopShared,
+    ) -> Result<(), SkkError> {
+        // accept は `metrics_listener` で行い、 poll には readable の通知にのみ使う
+        let mut listener = mio::net::TcpListener::from_std(metrics_listener.try_clone()?);
+        poll.registry()
+            .register(&mut listener, METRICS_LISTENER, Interest::READABLE)?;
+        let mut events = Events::with_capacity(1);
+        loop {
+            poll.poll(&mut events, None)?;
+            if shared.is_stopping() {
+                return Ok(());
+            }
+            if events.iter().all(|event| event.token() != METRICS_LISTENER) {
+                continue;
+            }
+            loop {
+                match metrics_listener.accept() {
+                    Ok((_, peer_address)) if !self.is_allowed_ip_address(peer_address.ip()) => {
+                        Self::log_error(&format!(
+                            "rejected metrics connection from {}",
+                            peer_address.ip()
+                        ));
+                    }
+                    Ok((stream, _)) => {
+                        if let Err(e) = self.handle_metrics_client(stream, shared) {
+                            Self::log_error(&format!("metrics client error={e}"));
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(SkkError::Io(e)),
+                }
+            }
+        }
+    }
+
+    fn handle_metrics_client(
+        &self,
+        stream: std::net::TcpStream,
+        shared: &RunLoopShared,
+    ) -> Result<(), SkkError> {
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(METRICS_TIMEOUT))?;
+        let mut buffer_stream = BufReader::new(DeadlineStream::new(stream, METRICS_TIMEOUT));
+        let mut request_line = String::new();
+        buffer_stream.read_line(&mut request_line)?;
+        // header は使わないが、読み終えてから response を返す
+        let mut header = String::new();
+        while buffer_stream.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+        let mut request = request_line.split_whitespace();
+        let method = request.next().unwrap_or_default();
+        let path = request.next().unwrap_or_default();
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let (status, body) = if method != "GET" {
+            ("405 Method Not Allowed", String::new())
+        } else if path == METRICS_PATH {
+            (
+                "200 OK",
+                self.server
+                    .dictionary
+                    .statistics
+                    .get_metrics_text(shared.connections.load(Ordering::SeqCst)),
+            )
+        } else {
+            ("404 Not Found", String::new())
+        };
+        let stream = buffer_stream.get_mut();
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: {METRICS_CONTENT_TYPE}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::ffi::CString;
+
+use crate::skk::yaskkserv2::{Config, SkkError, Yaskkserv2};
+
+const PASSWD_BUFFER_LENGTH: usize = 16 * 1024;
+
+impl Yaskkserv2 {
+    /// `config` の `group` と `user` に切り替える
+    ///
+    /// privileged port などを bind し終えてから呼ぶこと。 `group` を指定しない場合は `user` の
+    /// primary group に切り替え、 supplementary group はその group のみにする。
+    ///
+    /// upgrade で切り替え後の user が書き換えられるよう、 pid file の owner も切り替える。
+    pub(in crate::skk) fn drop_privileges(config: &Config) -> Result<(), SkkError> {
+        if config.user.is_empty() && config.group.is_empty() {
+            return Ok(());
+        }
+        let user_ids = if config.user.is_empty() {
+            None
+        } else {
+            Some(Self::get_user_ids(&config.user)?)
+        };
+        let gid = if config.group.is_empty() {
+            user_ids.map(|(_, gid)| gid)
+        } else {
+            Some(Self::get_group_id(&config.group)?)
+        };
+        if !config.pid_file_full_path.is_empty()
+            && std::path::Path::new(&config.pid_file_full_path).exists()
+        {
+            if let Err(e) = std::os::unix::fs::chown(
+                &config.pid_file_full_path,
+                user_ids.map(|(uid, _)| uid),
+                gid,
+            ) {
+                Self::log_warn(&format!(
+                    "pid file chown failed {}  error={e}",
+                    config.pid_file_full_path
+                ));
+            }
+        }
+        if let Some(gid) = gid {
+            if unsafe { libc::setgroups(1, std::ptr::addr_of!(gid)) } != 0
+                || unsafe { libc::setgid(gid) } != 0
+            {
+                return Err(SkkError::Io(std::io::Error::last_os_error()));
+            }
+        }
+        if let Some((uid, _)) = user_ids {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(SkkError::Io(std::io::Error::last_os_error()));
+            }
+        }
+        Self::log_info(&format!(
+            "privileges dropped user={} group={}",
+            config.user, config.group
+        ));
+        Ok(())
+    }
+
+    /// 終了時に pid file を削除する
+    ///
+    /// user を切り替えた後は pid file の directory に書き込めず削除できないことがある。その場合
+    /// は `drop_privileges()` で owner を切り替えてある pid file を空にし、古い pid を残さない。
+    pub(in crate::skk) fn remove_pid_file(config: &Config) {
+        if config.pid_file_full_path.is_empty() {
+            return;
+        }
+        let Err(remove_error) = std::fs::remove_file(&config.pid_file_full_path) else {
+            return;
+        };
+        if remove_error.kind() == std::io::ErrorKind::NotFound {
+            return;
+        }
+        if let Err(e) = std::fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&config.pid_file_full_path)
+        {
+            Self::log_warn(&format!(
+                "pid file remove failed {}  error={remove_error}  truncate error={e}",
+                config.pid_file_full_path
+            ));
+        }
+    }
+
+    /// `user` の (uid, primary gid) を返す
+    ///
+    /// 数値の場合は uid とみなす。 primary gid を得るため、 passwd に存在しない uid は
+    /// `Err` を返す。
+    pub(in crate::skk) fn get_user_ids(user: &str) -> Result<(libc::uid_t, libc::gid_t), SkkError> {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0; PASSWD_BUFFER_LENGTH];
+        let mut result = std::ptr::null_mut();
+        let error = if let Ok(uid) = user.parse::<libc::uid_t>() {
+            unsafe {
+                libc::getpwuid_r(
+                    uid,
+                    std::ptr::addr_of_mut!(passwd),
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    std::ptr::addr_of_mut!(result),
+                )
+            }
+        } else {
+            let name = CString::new(user).map_err(|_| Self::get_not_found_error("user", user))?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    std::ptr::addr_of_mut!(passwd),
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    std::ptr::addr_of_mut!(result),
+                )
+            }
+        };
+        if error != 0 || result.is_null() {
+            return Err(Self::get_not_found_error("user", user));
+        }
+        Ok((passwd.pw_uid, passwd.pw_gid))
+    }
+
+    /// `group` の gid を返す
+    ///
+    /// 数値の場合は group に存在しなくてもそのまま gid とみなす。
+    pub(in crate::skk) fn get_group_id(group: &str) -> Result<libc::gid_t, SkkError> {
+        if let Ok(gid) = group.parse::<libc::gid_t>() {
+            return Ok(gid);
+        }
+        let name = CString::new(group).map_err(|_| Self::get_not_found_error("group", group))?;
+        let mut group_entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0; PASSWD_BUFFER_LENGTH];
+        let mut result = std::ptr::null_mut();
+        let error = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                std::ptr::addr_of_mut!(group_entry),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                std::ptr::addr_of_mut!(result),
+            )
+        };
+        if error != 0 || result.is_null() {
+            return Err(Self::get_not_found_error("group", group));
+        }
+        Ok(group_entry.gr_gid)
+    }
+
+    fn get_not_found_error(kind: &str, name: &str) -> SkkError {
+        SkkError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{kind} {name} not found"),
+        ))
+    }
+}
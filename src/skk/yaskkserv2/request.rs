@@ -1,9 +1,9 @@
 use regex::Regex;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
 use std::convert::TryInto;
 
-use crate::skk::yaskkserv2::{
-    Request, SkkError, Yaskkserv2, GOOGLE_JAPANESE_INPUT_URL, GOOGLE_SUGGEST_URL,
-};
+use crate::skk::yaskkserv2::{Config, GoogleLimiter, Request, SkkError, Yaskkserv2};
 
 impl Request {
     fn is_utf8_hiragana(letter: [u8; 3]) -> bool {
@@ -63,6 +63,8 @@ impl Request {
         }
     }
 
+    // usize::is_multiple_of() は Rust 1.87 以降でしか使えないので使わない
+    #[allow(clippy::manual_is_multiple_of)]
     fn is_utf8_hiragana_only(candidate: &[u8]) -> bool {
         let length = candidate.len();
         if length % 3 != 0 || length < 3 {
@@ -76,6 +78,7 @@ impl Request {
         true
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn is_utf8_katakana_only(candidate: &[u8]) -> bool {
         let length = candidate.len();
         if length % 3 != 0 || length < 3 {
@@ -89,6 +92,7 @@ impl Request {
         true
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn is_utf8_hankaku_katakana_only(candidate: &[u8]) -> bool {
         let length = candidate.len();
         if length % 3 != 0 || length < 3 {
@@ -298,20 +302,19 @@ impl Request {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(in crate::skk) fn request_google_japanese_input(
-        protocol: &str,
+        client: &reqwest::blocking::Client,
+        limiter: &GoogleLimiter,
+        url: &str,
         midashi: &[u8],
-        timeout: u64,
         max_candidates_length: usize,
         is_insert_hiragana_only_candidate: bool,
         is_insert_katakana_only_candidate: bool,
         is_insert_hankaku_katakana_only_candidate: bool,
     ) -> Result<Vec<Vec<u8>>, SkkError> {
         let encoded_midashi: String = url::form_urlencoded::byte_serialize(midashi).collect();
-        let content = Self::request(
-            &format!("{protocol}{GOOGLE_JAPANESE_INPUT_URL}{encoded_midashi}"),
-            timeout,
-        )?;
+        let content = Self::request(client, limiter, &format!("{url}{encoded_midashi}"))?;
         let json = json::parse(&content)?;
         let result = if json.is_array() && json[0].is_array() && (json[0].len() >= 2) {
             Self::get_google_japanese_input_result(
@@ -333,16 +336,14 @@ impl Request {
     }
 
     pub(in crate::skk) fn request_google_suggest(
-        protocol: &str,
+        client: &reqwest::blocking::Client,
+        limiter: &GoogleLimiter,
+        url: &str,
         midashi: &[u8],
-        timeout: u64,
     ) -> Result<Vec<Vec<u8>>, SkkError> {
         let encoded_midashi: String = url::form_urlencoded::byte_serialize(midashi).collect();
         let mut result = Vec::new();
-        let content = Self::request(
-            &format!("{protocol}{GOOGLE_SUGGEST_URL}{encoded_midashi}"),
-            timeout,
-        )?;
+        let content = Self::request(client, limiter, &format!("{url}{encoded_midashi}"))?;
         // FIXME!
         // suggest は google japanese input とは異なり JSON ではなく XML で返ってくる。
         // ここでは正式な XML parser ではなく簡易的に取得していることに注意。
@@ -367,14 +368,78 @@ impl Request {
         }
     }
 
-    fn request(url: &str, timeout: u64) -> Result<String, SkkError> {
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_millis(timeout))
-            .build()
-            .map_err(|e| {
-                Yaskkserv2::log_error(&format!("reqwest::Client::builder()  error={e:?}"));
+    /// Google Japanese Input と Google Suggest の request に使う client を作成する
+    ///
+    /// client は keep-alive の connection を pool するので、 request ごとに作成せず使い回す
+    /// こと。
+    ///
+    /// `google_proxy` を指定しない場合は、 reqwest が環境変数 `HTTPS_PROXY`, `HTTP_PROXY`,
+    /// `ALL_PROXY` の proxy を使う。どちらの場合も環境変数 `NO_PROXY` に一致する host には
+    /// proxy を使わない。 `google_ca_certificate_full_path` の CA は system の CA に追加される。
+    pub(in crate::skk) fn create_client(
+        config: &Config,
+    ) -> Result<reqwest::blocking::Client, SkkError> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(
+            std::time::Duration::from_millis(config.google_timeout_milliseconds),
+        );
+        if !config.google_proxy.is_empty() {
+            builder = builder.proxy(
+                reqwest::Proxy::all(&config.google_proxy)?.no_proxy(reqwest::NoProxy::from_env()),
+            );
+        }
+        if !config.google_ca_certificate_full_path.is_empty() {
+            let pem = std::fs::read(&config.google_ca_certificate_full_path).map_err(|e| {
+                Yaskkserv2::log_error(&format!(
+                    "google ca certificate read failed {}  error={e}",
+                    config.google_ca_certificate_full_path
+                ));
                 e
             })?;
+            // reqwest の from_pem_bundle() は native-tls で PEM を読めないので、 DER にしてから渡す
+            let certificates = CertificateDer::pem_slice_iter(&pem)
+                .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+                .map_err(|e| e.to_string())
+                .and_then(|certificates| {
+                    if certificates.is_empty() {
+                        Err(String::from("certificate not found"))
+                    } else {
+                        Ok(certificates)
+                    }
+                })
+                .map_err(|e| {
+                    Yaskkserv2::log_error(&format!(
+                        "google ca certificate read failed {}  error={e}",
+                        config.google_ca_certificate_full_path
+                    ));
+                    SkkError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })?;
+            for certificate in certificates {
+                builder =
+                    builder.add_root_certificate(reqwest::Certificate::from_der(&certificate)?);
+            }
+        }
+        Ok(builder.build().map_err(|e| {
+            Yaskkserv2::log_error(&format!("reqwest::Client::builder()  error={e:?}"));
+            e
+        })?)
+    }
+
+    /// `limiter` が許可した場合のみ request し、その成否を `limiter` に記録する
+    fn request(
+        client: &reqwest::blocking::Client,
+        limiter: &GoogleLimiter,
+        url: &str,
+    ) -> Result<String, SkkError> {
+        limiter.acquire()?;
+        let result = Self::request_without_limiter(client, url);
+        limiter.record(result.is_ok());
+        result
+    }
+
+    fn request_without_limiter(
+        client: &reqwest::blocking::Client,
+        url: &str,
+    ) -> Result<String, SkkError> {
         let response = client.get(url).send().map_err(|e| {
             Yaskkserv2::log_error(&format!("get()  error={e:?}"));
             e
@@ -0,0 +1,416 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::skk::yaskkserv2::{Config, SkkError, Yaskkserv2};
+#[cfg(target_os = "linux")]
+use crate::skk::GoogleTiming;
+
+#[cfg(target_os = "linux")]
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+#[cfg(target_os = "linux")]
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// ABI 1 の `LANDLOCK_ACCESS_FS_EXECUTE` から `LANDLOCK_ACCESS_FS_MAKE_SYM` まで
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+/// directory ではない file の rule に指定できる access
+#[cfg(target_os = "linux")]
+const LANDLOCK_ACCESS_FS_FILE: u64 = LANDLOCK_ACCESS_FS_EXECUTE
+    | LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_READ_FILE
+    | LANDLOCK_ACCESS_FS_TRUNCATE
+    | LANDLOCK_ACCESS_FS_IOCTL_DEV;
+
+/// Google の API を呼ぶ場合に、 name resolution と TLS の証明書のために読む必要があるもの
+#[cfg(target_os = "linux")]
+const SANDBOX_GOOGLE_READ_PATHS: [&str; 4] = [
+    "/etc",
+    "/usr/lib/ssl",
+    "/usr/share/ca-certificates",
+    "/usr/local/share/ca-certificates",
+];
+/// name resolution で `dlopen()` される NSS module のためのもの
+#[cfg(target_os = "linux")]
+const SANDBOX_GOOGLE_LIBRARY_PATHS: [&str; 4] = ["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+#[cfg(target_os = "linux")]
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+#[cfg(target_os = "linux")]
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+
+/// server には不要で、 process の外に影響するものや権限を得るための syscall
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_personality,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+impl Yaskkserv2 {
+    /// Landlock で file system への access を、 seccomp で syscall を制限する
+    ///
+    /// bind と user の切り替えを終えてから、 thread を起動する前に呼ぶこと。 制限は解除できない。
+    ///
+    /// 許可するのは dictionary と config file の directory の読み込み、 Google Japanese Input
+    /// cache と access log の書き込み、 Google を使う場合の name resolution と TLS に必要な
+    /// file の読み込みのみ。 kernel が Landlock に対応していない場合は seccomp のみで続行する。
+    #[cfg(target_os = "linux")]
+    pub(in crate::skk) fn apply_sandbox(config: &Config) -> Result<(), SkkError> {
+        if !config.is_sandbox_enabled {
+            return Ok(());
+        }
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        if Self::apply_landlock(config)? {
+            Self::log_info("sandbox landlock enabled");
+        } else {
+            Self::log_warn(
+                "sandbox landlock is not supported by kernel, file system is not restricted",
+            );
+        }
+        Self::apply_seccomp()?;
+        Self::log_info("sandbox seccomp enabled");
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(in crate::skk) fn apply_sandbox(config: &Config) -> Result<(), SkkError> {
+        if !config.is_sandbox_enabled {
+            return Ok(());
+        }
+        Err(SkkError::Io(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "sandbox is supported only on Linux",
+        )))
+    }
+
+    /// Landlock に対応していない kernel の場合は `Ok(false)` を返す
+    #[cfg(target_os = "linux")]
+    fn apply_landlock(config: &Config) -> Result<bool, SkkError> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Ok(false);
+        }
+        let mut handled_access_fs = LANDLOCK_ACCESS_FS_ABI_1;
+        if abi >= 2 {
+            handled_access_fs |= LANDLOCK_ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled_access_fs |= LANDLOCK_ACCESS_FS_TRUNCATE;
+        }
+        if abi >= 5 {
+            handled_access_fs |= LANDLOCK_ACCESS_FS_IOCTL_DEV;
+        }
+        let ruleset_attr = LandlockRulesetAttr { handled_access_fs };
+        let ruleset_fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::addr_of!(ruleset_attr),
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset_fd < 0 {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset_fd as i32) };
+        for (full_path, access) in Self::get_landlock_rules(config) {
+            Self::add_landlock_rule(&ruleset, &full_path, access & handled_access_fs)?;
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        Ok(true)
+    }
+
+    /// (path, access) を返す
+    ///
+    /// dictionary は reload で rename により置き換えられることがあるため、 file ではなく
+    /// directory を対象にする。 config file は `/etc` などにあり directory 全体を読めるように
+    /// したくないので、 file のみを対象にする。このため rename で置き換えた config file は
+    /// reload できない。
+    ///
+    /// 終了時に削除する unix domain socket と pid file は、その directory からの削除を許可する。
+    #[cfg(target_os = "linux")]
+    fn get_landlock_rules(config: &Config) -> Vec<(String, u64)> {
+        fn get_parent(full_path: &str) -> String {
+            std::path::Path::new(full_path)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .map_or_else(|| String::from("."), |parent| parent.display().to_string())
+        }
+        let mut rules = vec![
+            (
+                get_parent(&config.dictionary_full_path),
+                LANDLOCK_ACCESS_FS_READ_FILE,
+            ),
+            (config.full_path.clone(), LANDLOCK_ACCESS_FS_READ_FILE),
+        ];
+        if config.is_google_cache_enabled {
+            let access = LANDLOCK_ACCESS_FS_READ_FILE
+                | LANDLOCK_ACCESS_FS_WRITE_FILE
+                | LANDLOCK_ACCESS_FS_TRUNCATE;
+            if std::path::Path::new(&config.google_cache_full_path).exists() {
+                rules.push((config.google_cache_full_path.clone(), access));
+            } else {
+                rules.push((
+                    get_parent(&config.google_cache_full_path),
+                    access | LANDLOCK_ACCESS_FS_MAKE_REG,
+                ));
+            }
+        }
+        if !config.access_log_full_path.is_empty() {
+            rules.push((
+                get_parent(&config.access_log_full_path),
+                LANDLOCK_ACCESS_FS_WRITE_FILE
+                    | LANDLOCK_ACCESS_FS_MAKE_REG
+                    | LANDLOCK_ACCESS_FS_REMOVE_FILE,
+            ));
+        }
+        for full_path in [
+            &config.listen_unix_socket,
+            &config.control_socket,
+            &config.pid_file_full_path,
+        ] {
+            if !full_path.is_empty() {
+                rules.push((get_parent(full_path), LANDLOCK_ACCESS_FS_REMOVE_FILE));
+            }
+        }
+        if !config.pid_file_full_path.is_empty() {
+            // 削除できない場合は空にするため
+            rules.push((
+                config.pid_file_full_path.clone(),
+                LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_TRUNCATE,
+            ));
+        }
+        if config.google_timing != GoogleTiming::Disable || config.is_google_suggest_enabled {
+            rules.extend(
+                SANDBOX_GOOGLE_READ_PATHS
+                    .iter()
+                    .map(|full_path| (String::from(*full_path), LANDLOCK_ACCESS_FS_READ_FILE)),
+            );
+            rules.extend(SANDBOX_GOOGLE_LIBRARY_PATHS.iter().map(|full_path| {
+                (
+                    String::from(*full_path),
+                    LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_EXECUTE,
+                )
+            }));
+            if !config.google_ca_certificate_full_path.is_empty() {
+                rules.push((
+                    config.google_ca_certificate_full_path.clone(),
+                    LANDLOCK_ACCESS_FS_READ_FILE,
+                ));
+            }
+        }
+        rules
+    }
+
+    /// `full_path` が存在しない場合は何もしない
+    #[cfg(target_os = "linux")]
+    fn add_landlock_rule(ruleset: &OwnedFd, full_path: &str, access: u64) -> Result<(), SkkError> {
+        let Ok(file) = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(full_path)
+        else {
+            return Ok(());
+        };
+        let allowed_access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & LANDLOCK_ACCESS_FS_FILE
+        };
+        let path_beneath_attr = LandlockPathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        if unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                std::ptr::addr_of!(path_beneath_attr),
+                0,
+            )
+        } != 0
+        {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// `SECCOMP_DENIED_SYSCALLS` は `EPERM` で失敗させ、他の architecture の syscall は
+    /// process を kill する
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn apply_seccomp() -> Result<(), SkkError> {
+        fn statement(code: u32, k: u32) -> libc::sock_filter {
+            jump(code, k, 0)
+        }
+        fn jump(code: u32, k: u32, jt: usize) -> libc::sock_filter {
+            libc::sock_filter {
+                code: u16::try_from(code).unwrap(),
+                jt: u8::try_from(jt).expect("seccomp filter too long"),
+                jf: 0,
+                k,
+            }
+        }
+        // 一致した場合に errno を返す (code, k)
+        let mut checks = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        checks.push((libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT));
+        checks.extend(SECCOMP_DENIED_SYSCALLS.iter().map(|syscall| {
+            (
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                u32::try_from(*syscall).unwrap(),
+            )
+        }));
+        let mut filter = vec![
+            statement(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_ARCH_OFFSET,
+            ),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_NR_OFFSET,
+            ),
+        ];
+        // 各 check から、 check の後の allow の次にある errno までの距離
+        for (index, (code, k)) in checks.iter().enumerate() {
+            filter.push(jump(*code, *k, checks.len() - index));
+        }
+        filter.push(statement(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ALLOW,
+        ));
+        #[allow(clippy::cast_sign_loss)]
+        filter.push(statement(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        ));
+        let program = libc::sock_fprog {
+            len: u16::try_from(filter.len()).expect("seccomp filter too long"),
+            filter: filter.as_mut_ptr(),
+        };
+        if unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                std::ptr::addr_of!(program),
+            )
+        } != 0
+        {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        not(any(target_arch = "x86_64", target_arch = "aarch64"))
+    ))]
+    fn apply_seccomp() -> Result<(), SkkError> {
+        Err(SkkError::Io(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "sandbox seccomp is supported only on x86_64 and aarch64",
+        )))
+    }
+}
@@ -1,8 +1,8 @@
 #[cfg(test)]
 use crate::skk::yaskkserv2::server::test_unix::ServerDebug;
 use crate::skk::yaskkserv2::{
-    BufReader, Config, DictionaryFile, DictionaryReader, HandleClientResult, OnMemory, Server,
-    SkkError, TcpStream, TcpStreamSkk, Yaskkserv2, PKG_VERSION, PROTOCOL_MAXIMUM_LENGTH,
+    AccessLog, AccessLogEntry, Config, DictionaryFile, DictionaryReader, HandleClientResult,
+    OnMemory, Server, SkkError, TcpStreamSkk, Yaskkserv2, PKG_VERSION, PROTOCOL_MAXIMUM_LENGTH,
     PROTOCOL_MINIMUM_LENGTH,
 };
 #[cfg(feature = "assert_paranoia")]
@@ -13,37 +13,44 @@ impl Server {
         Self {
             config: Config::new(),
             dictionary: DictionaryReader::new(),
+            access_log: AccessLog::new(&Config::new()),
         }
     }
 
     pub(in crate::skk) fn setup(&mut self, config: Config, on_memory: OnMemory) {
         self.config = config.clone();
+        self.access_log = AccessLog::new(&config);
         self.dictionary.setup(config, on_memory);
     }
 
-    pub(in crate::skk) fn handle_client(
+    pub(in crate::skk) fn handle_client<S: TcpStreamSkk>(
         &self,
-        buffer_stream: &mut BufReader<TcpStream>,
+        stream: &mut S,
         dictionary_file: &mut DictionaryFile,
         buffer: &mut [u8],
+        access_log_entry: &mut AccessLogEntry,
     ) -> HandleClientResult {
-        let stream = buffer_stream.get_mut();
         match buffer[0] {
             b'0' => return HandleClientResult::Exit,
             b'1' => {
                 if self.config.is_midashi_utf8 {
                     let utf8_to_euc_buffer = crate::skk::encoding_simple::Euc::encode(buffer);
                     if let Ok(mut utf8_to_euc_buffer) = utf8_to_euc_buffer {
-                        self.handle_client_protocol_1(
+                        return self.handle_client_protocol_1(
                             stream,
                             dictionary_file,
                             &mut utf8_to_euc_buffer,
+                            access_log_entry,
                         );
-                    } else {
-                        Self::send_and_log_protocol_error(stream, "1", &SkkError::Encoding);
                     }
+                    Self::send_and_log_protocol_error(stream, "1", &SkkError::Encoding);
                 } else {
-                    self.handle_client_protocol_1(stream, dictionary_file, buffer);
+                    return self.handle_client_protocol_1(
+                        stream,
+                        dictionary_file,
+                        buffer,
+                        access_log_entry,
+                    );
                 }
             }
             b'2' => stream.write_all_flush_ignore_error(format!("{PKG_VERSION} ").as_bytes()),
@@ -56,12 +63,18 @@ impl Server {
                             stream,
                             dictionary_file,
                             &mut utf8_to_euc_buffer,
+                            access_log_entry,
                         );
                     } else {
                         Self::send_and_log_protocol_error(stream, "4", &SkkError::Encoding);
                     }
                 } else {
-                    self.handle_client_protocol_4(stream, dictionary_file, buffer);
+                    self.handle_client_protocol_4(
+                        stream,
+                        dictionary_file,
+                        buffer,
+                        access_log_entry,
+                    );
                 }
             }
             _ => {
@@ -71,6 +84,34 @@ impl Server {
         HandleClientResult::Continue
     }
 
+    /// `HandleClientResult::Google` で返された protocol 1 の request を処理し、 client へ送信
+    /// する response を返す
+    ///
+    /// Google Japanese Input の呼び出しを含むため、 worker thread から呼ぶこと。
+    ///
+    /// response を作成した時点で `access_log_entry` を access log に書き込む。
+    pub(in crate::skk) fn handle_client_protocol_1_google(
+        &self,
+        dictionary_file: &mut DictionaryFile,
+        buffer: &mut [u8],
+        access_log_entry: &mut AccessLogEntry,
+    ) -> Vec<u8> {
+        let mut response = Vec::new();
+        match self.dictionary.read_candidates_with_sources(
+            dictionary_file,
+            buffer,
+            &mut access_log_entry.sources,
+        ) {
+            Ok(candidates) => {
+                access_log_entry.set_candidates(&candidates);
+                self.send_protocol_1_candidates(&mut response, buffer, candidates);
+            }
+            Err(e) => Self::send_and_log_protocol_error(&mut response, "1", &e),
+        }
+        self.access_log.write(access_log_entry);
+        response
+    }
+
     fn validate_buffer_for_protocol_1_and_4(buffer: &[u8]) -> bool {
         let buffer_len = buffer.len();
         if !(PROTOCOL_MINIMUM_LENGTH..=PROTOCOL_MAXIMUM_LENGTH).contains(&buffer_len) {
@@ -83,59 +124,79 @@ impl Server {
         true
     }
 
-    fn send_and_log_protocol_error(stream: &mut TcpStream, protocol: &str, e: &SkkError) {
+    fn send_and_log_protocol_error<S: TcpStreamSkk>(stream: &mut S, protocol: &str, e: &SkkError) {
         Yaskkserv2::log_error(&format!("protocol {protocol} error={e}"));
         let _ignore_error = stream.write_error_flush();
     }
 
-    fn handle_client_protocol_1(
+    /// Google Japanese Input を呼ぶ必要がある場合は送信せず `HandleClientResult::Google` を返す
+    fn handle_client_protocol_1<S: TcpStreamSkk>(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut S,
         dictionary_file: &mut DictionaryFile,
         buffer: &mut [u8],
-    ) {
+        access_log_entry: &mut AccessLogEntry,
+    ) -> HandleClientResult {
         if !Self::validate_buffer_for_protocol_1_and_4(buffer) {
             let _ignore_error = stream.write_error_flush();
-            return;
+            return HandleClientResult::Continue;
         }
-        match self.dictionary.read_candidates(dictionary_file, buffer) {
-            Ok(mut candidates) => {
-                if Yaskkserv2::is_empty_candidates(&candidates) {
-                    buffer[0] = b'4';
-                    if let Some(last) = buffer.last() {
-                        if *last == b'\n' || *last == b'\r' {
-                            stream.write_all_flush_ignore_error(buffer);
-                        } else {
-                            let mut lf_appended_buffer = Vec::from(buffer);
-                            lf_appended_buffer.push(b'\n');
-                            stream.write_all_flush_ignore_error(&lf_appended_buffer);
-                        }
-                    } else {
-                        Self::send_and_log_protocol_error(stream, "1", &SkkError::BrokenDictionary);
-                    }
+        match self.dictionary.read_candidates_without_google(
+            dictionary_file,
+            buffer,
+            &mut access_log_entry.sources,
+        ) {
+            Ok(Some(candidates)) => {
+                access_log_entry.set_candidates(&candidates);
+                self.send_protocol_1_candidates(stream, buffer, candidates);
+            }
+            Ok(None) => return HandleClientResult::Google(buffer.to_vec()),
+            Err(e) => Self::send_and_log_protocol_error(stream, "1", &e),
+        }
+        HandleClientResult::Continue
+    }
+
+    #[cfg_attr(not(test), allow(clippy::unused_self))]
+    fn send_protocol_1_candidates<S: TcpStreamSkk>(
+        &self,
+        stream: &mut S,
+        buffer: &mut [u8],
+        mut candidates: Vec<u8>,
+    ) {
+        if Yaskkserv2::is_empty_candidates(&candidates) {
+            buffer[0] = b'4';
+            if let Some(last) = buffer.last() {
+                if *last == b'\n' || *last == b'\r' {
+                    stream.write_all_flush_ignore_error(buffer);
+                } else {
+                    let mut lf_appended_buffer = Vec::from(buffer);
+                    lf_appended_buffer.push(b'\n');
+                    stream.write_all_flush_ignore_error(&lf_appended_buffer);
+                }
+            } else {
+                Self::send_and_log_protocol_error(stream, "1", &SkkError::BrokenDictionary);
+            }
+        } else {
+            candidates.push(b'\n');
+            #[cfg(not(test))]
+            stream.write_all_flush_ignore_error(&candidates);
+            #[cfg(test)]
+            {
+                if self.config.is_debug_send {
+                    Self::send_bytes_debug(stream, &candidates);
                 } else {
-                    candidates.push(b'\n');
-                    #[cfg(not(test))]
                     stream.write_all_flush_ignore_error(&candidates);
-                    #[cfg(test)]
-                    {
-                        if self.config.is_debug_send {
-                            Self::send_bytes_debug(stream, &candidates);
-                        } else {
-                            stream.write_all_flush_ignore_error(&candidates);
-                        }
-                    }
                 }
             }
-            Err(e) => Self::send_and_log_protocol_error(stream, "1", &e),
         }
     }
 
-    fn handle_client_protocol_4(
+    fn handle_client_protocol_4<S: TcpStreamSkk>(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut S,
         dictionary_file: &mut DictionaryFile,
         buffer: &mut [u8],
+        access_log_entry: &mut AccessLogEntry,
     ) {
         if !Self::validate_buffer_for_protocol_1_and_4(buffer) {
             let _ignore_error = stream.write_error_flush();
@@ -143,6 +204,8 @@ impl Server {
         }
         match self.dictionary.read_abbrev(dictionary_file, buffer) {
             Ok(mut candidates) => {
+                access_log_entry.sources.dictionary = !Yaskkserv2::is_empty_candidates(&candidates);
+                access_log_entry.set_candidates(&candidates);
                 if Yaskkserv2::is_empty_candidates(&candidates) {
                     if let Some(last) = buffer.last() {
                         if *last == b'\n' || *last == b'\r' {
@@ -167,12 +230,10 @@ impl Server {
 
 #[cfg(test)]
 pub(in crate::skk) mod test_unix {
-    use crate::skk::yaskkserv2::{
-        DictionaryFile, Server, TcpStream, TcpStreamSkk, Write, Yaskkserv2,
-    };
+    use crate::skk::yaskkserv2::{DictionaryFile, Server, TcpStreamSkk, Write, Yaskkserv2};
 
     pub(in crate::skk) trait ServerDebug {
-        fn send_bytes_debug(stream: &mut TcpStream, buffer: &[u8]);
+        fn send_bytes_debug<S: TcpStreamSkk>(stream: &mut S, buffer: &[u8]);
         fn send_bytes_std_net_tcp(stream: &std::net::TcpStream, buffer: &[u8]);
         fn handle_client_protocol_1_simple_std_net_tcp(
             &self,
@@ -183,7 +244,7 @@ pub(in crate::skk) mod test_unix {
     }
 
     impl ServerDebug for Server {
-        fn send_bytes_debug(stream: &mut TcpStream, buffer: &[u8]) {
+        fn send_bytes_debug<S: TcpStreamSkk>(stream: &mut S, buffer: &[u8]) {
             stream.write_all_flush_ignore_error(buffer);
         }
 
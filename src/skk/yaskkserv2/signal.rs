@@ -0,0 +1,60 @@
+use signal_hook::consts::{SIGHUP, SIGUSR1, SIGUSR2};
+#[cfg(not(test))]
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::{Handle, Signals};
+
+use crate::skk::yaskkserv2::{Ordering, RunLoopShared, SkkError, Yaskkserv2};
+
+impl Yaskkserv2 {
+    /// signal を処理する thread を起動する
+    ///
+    /// 戻り値の `Handle` を `close()` すると thread は終了する。 `scope` を抜ける前に必ず
+    /// `close()` すること。
+    ///
+    /// - SIGHUP: config file を読み直し、 dictionary を reload する
+    /// - SIGUSR1: 統計を log に出力する
+    /// - SIGUSR2: 新しい binary に upgrade する
+    /// - SIGTERM, SIGINT: 処理中の response を送信し終えてから終了する
+    ///
+    /// test では test process の Ctrl-C などを妨げないよう、 SIGTERM と SIGINT は扱わない。
+    pub(in crate::skk) fn spawn_signal_handler<'scope>(
+        &'scope self,
+        scope: &'scope std::thread::Scope<'scope, '_>,
+        shared: &'scope RunLoopShared,
+    ) -> Result<Handle, SkkError> {
+        #[cfg(not(test))]
+        let mut signals = Signals::new([SIGHUP, SIGUSR1, SIGUSR2, SIGTERM, SIGINT])?;
+        #[cfg(test)]
+        let mut signals = Signals::new([SIGHUP, SIGUSR1, SIGUSR2])?;
+        let handle = signals.handle();
+        std::thread::Builder::new()
+            .name(String::from("signal"))
+            .spawn_scoped(scope, move || {
+                for signal in signals.forever() {
+                    match signal {
+                        SIGHUP => {
+                            // 失敗は reload_config() と reload_dictionary() が log に出力する
+                            let _ignore_error = self.reload_config();
+                            let _ignore_error = self.reload_dictionary();
+                            // idle timeout の変更を poll loop に反映させる
+                            shared.wake_all();
+                        }
+                        SIGUSR1 => {
+                            self.log_statistics(shared.connections.load(Ordering::SeqCst));
+                        }
+                        SIGUSR2 => {
+                            // 失敗は upgrade() が log に出力する
+                            let _ignore_error = self.upgrade(shared);
+                        }
+                        #[cfg(not(test))]
+                        SIGTERM | SIGINT => {
+                            Self::log_info(&format!("signal {signal} received, shutting down"));
+                            shared.shutdown();
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+        Ok(handle)
+    }
+}
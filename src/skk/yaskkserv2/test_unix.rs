@@ -3,8 +3,8 @@ use std::sync::{Arc, Mutex, RwLock};
 
 use crate::skk::test_unix::{Path, MANY_THREAD_MUTEX_LOCK};
 use crate::skk::yaskkserv2::{
-    BufRead, DictionaryFile, File, GoogleCache, Server, ServerDebug, Shutdown, Yaskkserv2,
-    INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH,
+    BufRead, DictionaryFile, File, GoogleCache, Listener, Server, ServerDebug, Shutdown,
+    Yaskkserv2, INITIAL_DICTIONARY_FILE_READ_BUFFER_LENGTH,
 };
 
 pub(in crate::skk) trait Yaskkserv2Debug {
@@ -14,14 +14,19 @@ pub(in crate::skk) trait Yaskkserv2Debug {
 
 impl Yaskkserv2Debug for Yaskkserv2 {
     fn run_test(&mut self, take_count_for_test: usize) {
-        self.run_loop(take_count_for_test).unwrap();
+        let listeners = Listener::bind_all(&self.server.config).unwrap();
+        self.run_loop(&listeners, None, take_count_for_test)
+            .unwrap();
     }
 
     fn run_test_simple_std_net_tcp(&mut self, take_count_for_test: usize) {
-        let listener = match std::net::TcpListener::bind(format!(
-            "{}:{}",
-            &self.server.config.listen_address, &self.server.config.port
-        )) {
+        let listener = match std::net::TcpListener::bind(
+            Listener::parse_listen_address(
+                &self.server.config.listen_addresses[0],
+                &self.server.config.port,
+            )
+            .unwrap(),
+        ) {
             Ok(ok) => ok,
             Err(e) => {
                 let message = format!("bind failed {e}");
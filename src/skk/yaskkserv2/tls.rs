@@ -0,0 +1,54 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::skk::yaskkserv2::{Arc, Config, Listener, SkkError};
+
+impl Listener {
+    /// config で指定された certificate と private key から TLS の設定を作成する
+    ///
+    /// `tls_client_ca_certificate_full_path` が指定されている場合は、その CA で署名された
+    /// client certificate を持つ client のみ接続できる。
+    pub(in crate::skk) fn get_tls_server_config(
+        config: &Config,
+    ) -> Result<Arc<rustls::ServerConfig>, SkkError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = if config.tls_client_ca_certificate_full_path.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let mut root_cert_store = rustls::RootCertStore::empty();
+            for certificate in
+                Self::read_tls_certificates(&config.tls_client_ca_certificate_full_path)?
+            {
+                root_cert_store.add(certificate)?;
+            }
+            let client_cert_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+                Arc::new(root_cert_store),
+                provider,
+            )
+            .build()
+            .map_err(|e| Self::get_tls_invalid_data_error(&e))?;
+            builder.with_client_cert_verifier(client_cert_verifier)
+        };
+        let server_config = builder.with_single_cert(
+            Self::read_tls_certificates(&config.tls_certificate_full_path)?,
+            PrivateKeyDer::from_pem_file(&config.tls_private_key_full_path)
+                .map_err(|e| Self::get_tls_invalid_data_error(&e))?,
+        )?;
+        Ok(Arc::new(server_config))
+    }
+
+    fn read_tls_certificates(full_path: &str) -> Result<Vec<CertificateDer<'static>>, SkkError> {
+        CertificateDer::pem_file_iter(full_path)
+            .and_then(Iterator::collect)
+            .map_err(|e| Self::get_tls_invalid_data_error(&e))
+    }
+
+    fn get_tls_invalid_data_error<E: std::fmt::Display>(e: &E) -> SkkError {
+        SkkError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            e.to_string(),
+        ))
+    }
+}
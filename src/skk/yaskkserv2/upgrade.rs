@@ -0,0 +1,216 @@
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+
+use crate::skk::yaskkserv2::{
+    File, GoogleCache, Instant, Listener, Ordering, Read, RunLoopShared, SkkError, UpgradeFds,
+    Yaskkserv2, PKG_VERSION, UPGRADE_CONTROL_FD_ENV, UPGRADE_LISTENER_FDS_ENV,
+    UPGRADE_METRICS_FD_ENV, UPGRADE_READY_FD_ENV, UPGRADE_READY_TIMEOUT,
+};
+
+impl Yaskkserv2 {
+    /// upgrade 元の process から起動されたかどうかを返す
+    pub(in crate::skk) fn is_upgrade() -> bool {
+        std::env::var_os(UPGRADE_LISTENER_FDS_ENV).is_some()
+    }
+
+    /// upgrade 元の process から継承した listening socket で server を実行する
+    ///
+    /// dictionary は `setup()` で読み込み済みであること。
+    pub(in crate::skk) fn run_upgrade(&mut self) {
+        Self::log_info(&format!("version {PKG_VERSION} (upgrade)"));
+        match Listener::from_upgrade(&self.server.config) {
+            Ok((listeners, handover)) => self.run_listeners(&listeners, Some(handover)),
+            Err(e) => {
+                let message = format!("upgrade failed {e}");
+                Self::log_error(&message);
+                Self::print_warning(&message);
+            }
+        }
+    }
+
+    /// upgrade で listening socket を新しい process に渡したかどうかを返す
+    pub(in crate::skk) fn is_upgraded(&self) -> bool {
+        self.is_upgraded.load(Ordering::SeqCst)
+    }
+
+    /// 起動時と同じ binary と引数で新しい process を起動し、 listening socket を渡す
+    ///
+    /// 新しい process が dictionary を読み込んで ready を通知するまでは、この process が
+    /// 受け付けを続ける。 ready の後は新しい connection を受け付けず、既存の connection を
+    /// drain してから終了する。失敗した場合は新しい process を終了させ、この process が
+    /// そのまま受け付けを続ける。
+    pub(in crate::skk) fn upgrade(&self, shared: &RunLoopShared) -> Result<(), SkkError> {
+        if shared.is_shutdown.load(Ordering::SeqCst)
+            || shared.is_upgrading.swap(true, Ordering::SeqCst)
+        {
+            let e = SkkError::Io(std::io::Error::other("upgrade or shutdown is in progress"));
+            Self::log_error(&format!("upgrade failed error={e}"));
+            return Err(e);
+        }
+        match self.spawn_upgrade_process(&shared.upgrade_fds) {
+            Ok(pid) => {
+                self.is_upgraded.store(true, Ordering::SeqCst);
+                Self::log_info(&format!("upgraded to pid={pid}, draining connections"));
+                shared.drain();
+                Ok(())
+            }
+            Err(e) => {
+                shared.is_upgrading.store(false, Ordering::SeqCst);
+                Self::log_error(&format!("upgrade failed error={e}"));
+                Err(e)
+            }
+        }
+    }
+
+    /// 新しい process を起動し、 ready を通知されたらその pid を返す
+    ///
+    /// pid file を指定している場合は、新しい process の pid に書き換える。
+    fn spawn_upgrade_process(&self, upgrade_fds: &UpgradeFds) -> Result<u32, SkkError> {
+        let config = self.server.dictionary.get_config();
+        // execve() が seccomp で禁止されている
+        if config.is_sandbox_enabled {
+            return Err(SkkError::Io(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "upgrade is not available with sandbox",
+            )));
+        }
+        let Some((full_path, arguments)) = &self.upgrade_command else {
+            return Err(SkkError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "executable not found",
+            )));
+        };
+        // 新しい process が最新の cache を読み込めるよう、起動前に書き出す
+        if config.is_google_cache_enabled {
+            GoogleCache::flush(&config.google_cache_full_path)?;
+        }
+        let (ready_reader, ready_writer) = Self::create_pipe()?;
+        let mut inherited_fds = upgrade_fds
+            .listeners
+            .iter()
+            .map(|(_, fd)| *fd)
+            .chain(upgrade_fds.control)
+            .chain(upgrade_fds.metrics)
+            .collect::<Vec<RawFd>>();
+        inherited_fds.push(ready_writer.as_raw_fd());
+        let mut command = std::process::Command::new(full_path);
+        command
+            .args(arguments)
+            .env(
+                UPGRADE_LISTENER_FDS_ENV,
+                upgrade_fds
+                    .listeners
+                    .iter()
+                    .map(|(kind, fd)| format!("{kind}:{fd}"))
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .env(UPGRADE_READY_FD_ENV, ready_writer.as_raw_fd().to_string());
+        if let Some(fd) = upgrade_fds.control {
+            command.env(UPGRADE_CONTROL_FD_ENV, fd.to_string());
+        }
+        if let Some(fd) = upgrade_fds.metrics {
+            command.env(UPGRADE_METRICS_FD_ENV, fd.to_string());
+        }
+        // fork() から exec() までの間に呼ばれるため、 async-signal-safe な処理のみ行うこと
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &inherited_fds {
+                    if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{} {e}", full_path.display())))?;
+        // 新しい process が ready を通知せずに終了した場合に EOF となるよう、ここで閉じる
+        drop(ready_writer);
+        if let Err(e) = Self::wait_upgrade_ready(&ready_reader) {
+            let _ignore_error = child.kill();
+            let _ignore_error = child.wait();
+            return Err(e);
+        }
+        let pid = child.id();
+        if !config.pid_file_full_path.is_empty() {
+            if let Err(e) = std::fs::write(&config.pid_file_full_path, format!("{pid}\n")) {
+                Self::log_error(&format!(
+                    "pid file write failed {}  error={e}",
+                    config.pid_file_full_path
+                ));
+            }
+        }
+        Ok(pid)
+    }
+
+    /// close-on-exec の pipe を作成し、 (読み込み側, 書き込み側) を返す
+    fn create_pipe() -> Result<(File, File), SkkError> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(SkkError::Io(std::io::Error::last_os_error()));
+        }
+        let pipe = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        for fd in fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(SkkError::Io(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(pipe)
+    }
+
+    /// 新しい process が `ready` に書き込むまで `UPGRADE_READY_TIMEOUT` 待つ
+    fn wait_upgrade_ready(mut ready: &File) -> Result<(), SkkError> {
+        let deadline = Instant::now() + UPGRADE_READY_TIMEOUT;
+        let mut poll_fd = libc::pollfd {
+            fd: ready.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let timeout_milliseconds = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+            match unsafe { libc::poll(std::ptr::addr_of_mut!(poll_fd), 1, timeout_milliseconds) } {
+                -1 => {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(SkkError::Io(e));
+                    }
+                }
+                0 => {
+                    return Err(SkkError::Io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "new process is not ready",
+                    )))
+                }
+                _ => break,
+            }
+        }
+        let mut byte = [0; 1];
+        if ready.read(&mut byte)? == 0 {
+            return Err(SkkError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "new process exited before ready",
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl UpgradeFds {
+    pub(in crate::skk) fn new(
+        listeners: &[Listener],
+        control_listener: Option<&Listener>,
+        metrics_listener: Option<&std::net::TcpListener>,
+    ) -> Self {
+        Self {
+            listeners: listeners
+                .iter()
+                .map(Listener::get_upgrade_kind_and_fd)
+                .collect(),
+            control: control_listener.map(|listener| listener.get_upgrade_kind_and_fd().1),
+            metrics: metrics_listener.map(AsRawFd::as_raw_fd),
+        }
+    }
+}